@@ -6,41 +6,129 @@
 use std::collections::VecDeque;
 use super::compiler::Label;
 use std::io::Write;
+use std::fmt;
+use interpreter::{dump_heading, DUMP_WINDOW};
+use parse::Span;
 
-#[derive(Debug)]
+/// The number of cells on the tape of a compiled program.
+pub const TAPE_SIZE: usize = 80000;
+
+/// What comes between the data pointer and the number of the first cell in a `Dump`.
+const DUMP_CELLS: &str = " cells ";
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ByteCode {
   Add(isize),
   Sub(isize),
   MoveRight(isize),
   MoveLeft(isize),
+  AddAt(isize, isize),
+  SubAt(isize, isize),
+  AddVector(isize, Vec<u8>),
+  Clear,
+  ScanRight(isize),
+  ScanLeft(isize),
+  MultiplyAdd(isize, isize),
   Read,
   Write,
+  WriteBytes(Vec<u8>),
+  /// Write the data pointer and the cells around it to stderr, for a '#' at the given position
+  /// when it's been opted into
+  Dump(Span),
   Jump(Label),
   JumpNotZero(Label),
+  ExitIfZero(Label),
   Exit,
 }
 
 #[derive(Debug)]
 pub struct ByteProgram {
   program: VecDeque<ByteCode>,
+  cold_loops: Vec<Label>,
 }
 
 impl ByteProgram {
   pub fn new() -> Self {
     ByteProgram {
       program: VecDeque::new(),
+      cold_loops: Vec::new(),
     }
   }
 
+  /// Lay the loop with the given label out after the rest of the program, keeping code that rarely
+  /// runs out of the way.
+  pub fn mark_cold(&mut self, label: Label) {
+    self.cold_loops.push(label);
+  }
+
   pub fn emit<W: Write>(&self, out: &mut W) {
     // For now write to stdout, in the future we can use a writer
     emit_prelude(out);
 
+    let mut state = EmitState::default();
+    let mut cold = Vec::new();
     for byte_code in self.program.iter() {
-      compile_to_native_code(byte_code, out);
+      if state.cold_depth == 0 {
+        match *byte_code {
+          ByteCode::Jump(ref label) if self.cold_loops.contains(label) => {
+            write_all(out, "  cmp byte [rsp], 0\n");
+            write_all(out, &format!("  jne {}\n", label));
+            write_all(out, &format!("{}_back:\n", label));
+            write_all(&mut cold, &format!("{}:\n", label));
+            state.cold_depth = 1;
+          },
+          _ => compile_to_native_code(byte_code, &mut state, out),
+        }
+        continue;
+      }
+
+      match *byte_code {
+        ByteCode::Jump(_) => state.cold_depth += 1,
+        ByteCode::JumpNotZero(_) => state.cold_depth -= 1,
+        _ => {},
+      }
+      compile_to_native_code(byte_code, &mut state, &mut cold);
+      if state.cold_depth == 0 {
+        if let ByteCode::JumpNotZero(ref label) = *byte_code {
+          write_all(&mut cold, &format!("  jmp {}_back\n", label));
+        }
+      }
     }
+    if let Err(err) = out.write_all(&cold) {
+      panic!("{}", err);
+    }
+    if !state.dumps.is_empty() {
+      emit_dump_routine(out);
+    }
+
+    emit_data(out, &state);
+    emit_bss(out, &state);
+  }
+}
 
-    emit_bss(out);
+impl fmt::Display for ByteCode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ByteCode::Add(num) => write!(f, "add {}", num),
+      ByteCode::Sub(num) => write!(f, "sub {}", num),
+      ByteCode::MoveRight(num) => write!(f, "right {}", num),
+      ByteCode::MoveLeft(num) => write!(f, "left {}", num),
+      ByteCode::AddAt(offset, num) => write!(f, "add [{:+}] {}", offset, num),
+      ByteCode::SubAt(offset, num) => write!(f, "sub [{:+}] {}", offset, num),
+      ByteCode::AddVector(offset, ref bytes) => write!(f, "vadd [{:+}] {:?}", offset, bytes),
+      ByteCode::Clear => write!(f, "clear"),
+      ByteCode::ScanRight(num) => write!(f, "scan right {}", num),
+      ByteCode::ScanLeft(num) => write!(f, "scan left {}", num),
+      ByteCode::MultiplyAdd(offset, factor) => write!(f, "mul [{:+}] {}", offset, factor),
+      ByteCode::Read => write!(f, "read"),
+      ByteCode::Write => write!(f, "write"),
+      ByteCode::WriteBytes(ref bytes) => write!(f, "write {:?}", String::from_utf8_lossy(bytes)),
+      ByteCode::Dump(span) => write!(f, "dump {}:{}", span.line, span.column),
+      ByteCode::Jump(ref label) => write!(f, "jump {}", label),
+      ByteCode::JumpNotZero(ref label) => write!(f, "jnz {}", label),
+      ByteCode::ExitIfZero(ref label) => write!(f, "exitz {}", label),
+      ByteCode::Exit => write!(f, "exit"),
+    }
   }
 }
 
@@ -48,6 +136,7 @@ impl From<VecDeque<ByteCode>> for ByteProgram {
   fn from(byte_code: VecDeque<ByteCode>) -> Self {
     ByteProgram {
       program: byte_code,
+      cold_loops: Vec::new(),
     }
   }
 }
@@ -58,6 +147,33 @@ impl From<ByteProgram> for VecDeque<ByteCode> {
   }
 }
 
+/// State that needs to be kept between instructions while emitting native code.
+#[derive(Default)]
+struct EmitState {
+  scan_count: usize,
+  // The start of the line written by each place the tape is dumped from, each one also needs its
+  // own label to return to
+  dumps: Vec<Vec<u8>>,
+  // How deeply nested we are in a loop being laid out after the rest of the program
+  cold_depth: usize,
+  constants: Vec<Vec<u8>>,
+  // Vectors added to the tape, each one is only emitted once no matter how often it's used
+  vectors: Vec<Vec<u8>>,
+}
+
+impl EmitState {
+  /// The index of `bytes` in the vector constant pool, adding it to the pool if it isn't there.
+  fn vector_constant(&mut self, bytes: &[u8]) -> usize {
+    match self.vectors.iter().position(|vector| vector.as_slice() == bytes) {
+      Some(index) => index,
+      None => {
+        self.vectors.push(bytes.to_vec());
+        self.vectors.len() - 1
+      },
+    }
+  }
+}
+
 fn emit_prelude<W: Write>(out: &mut W) {
   write_all(out, "global start\n");
   write_all(out, "\n");
@@ -67,13 +183,51 @@ fn emit_prelude<W: Write>(out: &mut W) {
   write_all(out, "  mov rsp, tape\n");
 }
 
-fn compile_to_native_code<W: Write>(byte_code: &ByteCode, out: &mut W) {
+fn compile_to_native_code<W: Write>(byte_code: &ByteCode, state: &mut EmitState, out: &mut W) {
   match *byte_code {
     ByteCode::Add(num) => { write_all(out, &format!("  add byte [rsp], {}\n", num)); },
     ByteCode::Sub(num) => { write_all(out, &format!("  sub byte [rsp], {}\n", num)); },
     ByteCode::MoveRight(num) => { write_all(out, &format!("  add rsp, {}\n", num)); },
-    // TODO: Use rsp by offset, saturating sub for offset reg
     ByteCode::MoveLeft(num) => { write_all(out, &format!("  sub rsp, {}\n", num)); },
+    ByteCode::AddAt(offset, num) => { write_all(out, &format!("  add byte [rsp{:+}], {}\n", offset, num)); },
+    ByteCode::SubAt(offset, num) => { write_all(out, &format!("  sub byte [rsp{:+}], {}\n", offset, num)); },
+    ByteCode::AddVector(offset, ref bytes) => {
+      let index = state.vector_constant(bytes);
+      if bytes.len() == 32 {
+        write_all(out, &format!("  vmovdqu ymm0, [rsp{:+}]\n", offset));
+        write_all(out, &format!("  vpaddb ymm0, ymm0, [rel VEC{}]\n", index));
+        write_all(out, &format!("  vmovdqu [rsp{:+}], ymm0\n", offset));
+      }
+      else {
+        write_all(out, &format!("  movdqu xmm0, [rsp{:+}]\n", offset));
+        write_all(out, &format!("  paddb xmm0, [rel VEC{}]\n", index));
+        write_all(out, &format!("  movdqu [rsp{:+}], xmm0\n", offset));
+      }
+    },
+    ByteCode::Clear => { write_all(out, "  mov byte [rsp], 0\n"); },
+    ByteCode::ScanRight(1) => {
+      write_all(out, "  mov rdi, rsp\n");
+      write_all(out, "  xor eax, eax\n");
+      write_all(out, "  mov rcx, -1\n");
+      write_all(out, "  repne scasb\n");
+      write_all(out, "  lea rsp, [rdi - 1]\n");
+    },
+    ByteCode::ScanLeft(1) => {
+      write_all(out, "  mov rdi, rsp\n");
+      write_all(out, "  xor eax, eax\n");
+      write_all(out, "  mov rcx, -1\n");
+      write_all(out, "  std\n");
+      write_all(out, "  repne scasb\n");
+      write_all(out, "  cld\n");
+      write_all(out, "  lea rsp, [rdi + 1]\n");
+    },
+    ByteCode::MultiplyAdd(offset, factor) => {
+      write_all(out, "  movzx eax, byte [rsp]\n");
+      write_all(out, &format!("  imul eax, eax, {}\n", factor));
+      write_all(out, &format!("  add byte [rsp{:+}], al\n", offset));
+    },
+    ByteCode::ScanRight(num) => { emit_scan_loop(out, state, "add", num); },
+    ByteCode::ScanLeft(num) => { emit_scan_loop(out, state, "sub", num); },
     ByteCode::Read => {
       write_all(out, "  mov rax, 0x2000003 ; read\n");
       write_all(out, "  mov rdi, 0         ; stdin\n");
@@ -88,6 +242,25 @@ fn compile_to_native_code<W: Write>(byte_code: &ByteCode, out: &mut W) {
       write_all(out, "  mov rdx, 1\n");
       write_all(out, "  syscall\n");
     },
+    ByteCode::WriteBytes(ref bytes) => {
+      write_all(out, "  mov rax, 0x2000004 ; write\n");
+      write_all(out, "  mov rdi, 1         ; stdout\n");
+      write_all(out, &format!("  mov rsi, CONST{}\n", state.constants.len()));
+      write_all(out, &format!("  mov rdx, {}\n", bytes.len()));
+      write_all(out, "  syscall\n");
+      state.constants.push(bytes.clone());
+    },
+    ByteCode::Dump(span) => {
+      // The line starts the same way as the interpreter's, with the position and "pointer "
+      let heading = format!("{}pointer ", dump_heading(span)).into_bytes();
+      write_all(out, &format!("  lea rsi, [rel DUMP_AT{}]\n", state.dumps.len()));
+      write_all(out, &format!("  mov ecx, {}\n", heading.len()));
+      // `rsp` is the data pointer so the routine can't be `call`ed, it returns through `rbx`
+      write_all(out, &format!("  lea rbx, [rel DUMP_RETURN{}]\n", state.dumps.len()));
+      write_all(out, "  jmp DUMP\n");
+      write_all(out, &format!("DUMP_RETURN{}:\n", state.dumps.len()));
+      state.dumps.push(heading);
+    },
     ByteCode::Jump(ref label) => {
       write_all(out, &format!("  jmp _{}\n", label));
       write_all(out, &format!("{}:\n", label));
@@ -97,6 +270,10 @@ fn compile_to_native_code<W: Write>(byte_code: &ByteCode, out: &mut W) {
       write_all(out, "  cmp byte [rsp], 0\n");
       write_all(out, &format!("  jne {}\n", label));
     },
+    ByteCode::ExitIfZero(ref label) => {
+      write_all(out, "  cmp byte [rsp], 0\n");
+      write_all(out, &format!("  je _{}\n", label));
+    },
     ByteCode::Exit => {
       write_all(out, "  mov rax, 0x2000001 ; exit\n");
       write_all(out, "  mov rdi, 0\n");
@@ -105,9 +282,137 @@ fn compile_to_native_code<W: Write>(byte_code: &ByteCode, out: &mut W) {
   }
 }
 
-fn emit_bss<W: Write>(out: &mut W) {
+/// Emit a loop stepping the data pointer by more than one cell until it lands on a zero cell.
+fn emit_scan_loop<W: Write>(out: &mut W, state: &mut EmitState, op: &str, num: isize) {
+  let label = format!("SCAN{}", state.scan_count);
+  state.scan_count += 1;
+  write_all(out, &format!("  jmp _{}\n", label));
+  write_all(out, &format!("{}:\n", label));
+  write_all(out, &format!("  {} rsp, {}\n", op, num));
+  write_all(out, &format!("_{}:\n", label));
+  write_all(out, "  cmp byte [rsp], 0\n");
+  write_all(out, &format!("  jne {}\n", label));
+}
+
+/// Emit the routine `Dump` jumps to with the heading of the line to write in `rsi` and its length
+/// in `ecx`. It writes the line to stderr in the same format as the interpreter, then jumps back
+/// to the address in `rbx`.
+///
+/// Every cell within `DUMP_WINDOW` of the data pointer is shown, the interpreter leaves out cells
+/// to the right that the program hasn't reached yet but they're all zero.
+fn emit_dump_routine<W: Write>(out: &mut W) {
+  write_all(out, "DUMP:\n");
+  write_all(out, "  lea rdi, [rel DUMP_BUFFER]\n");
+  write_all(out, "  rep movsb\n");
+  write_all(out, "  lea r9, [rel tape]\n");
+  write_all(out, "  mov rax, rsp\n");
+  write_all(out, "  sub rax, r9\n");
+  emit_decimal(out, "POINTER");
+  write_all(out, "  lea rsi, [rel DUMP_CELLS]\n");
+  write_all(out, &format!("  mov ecx, {}\n", DUMP_CELLS.len()));
+  write_all(out, "  rep movsb\n");
+  // Only the cells on the tape are shown, from r8 up to but not including r12
+  write_all(out, &format!("  lea r8, [rsp - {}]\n", DUMP_WINDOW));
+  write_all(out, "  cmp r8, r9\n");
+  write_all(out, "  cmovb r8, r9\n");
+  write_all(out, &format!("  lea r12, [rsp + {}]\n", DUMP_WINDOW + 1));
+  write_all(out, &format!("  lea r13, [r9 + {}]\n", TAPE_SIZE));
+  write_all(out, "  cmp r12, r13\n");
+  write_all(out, "  cmova r12, r13\n");
+  write_all(out, "  mov rax, r8\n");
+  write_all(out, "  sub rax, r9\n");
+  emit_decimal(out, "FIRST");
+  write_all(out, "  mov al, ':'\n");
+  write_all(out, "  stosb\n");
+  write_all(out, "DUMP_CELL:\n");
+  write_all(out, "  mov al, ' '\n");
+  write_all(out, "  stosb\n");
+  write_all(out, "  cmp r8, rsp\n");
+  write_all(out, "  jne DUMP_VALUE\n");
+  write_all(out, "  mov al, '['\n");
+  write_all(out, "  stosb\n");
+  write_all(out, "DUMP_VALUE:\n");
+  write_all(out, "  movzx eax, byte [r8]\n");
+  emit_decimal(out, "VALUE");
+  write_all(out, "  cmp r8, rsp\n");
+  write_all(out, "  jne DUMP_NEXT\n");
+  write_all(out, "  mov al, ']'\n");
+  write_all(out, "  stosb\n");
+  write_all(out, "DUMP_NEXT:\n");
+  write_all(out, "  inc r8\n");
+  write_all(out, "  cmp r8, r12\n");
+  write_all(out, "  jb DUMP_CELL\n");
+  write_all(out, "  mov al, 10\n");
+  write_all(out, "  stosb\n");
+  write_all(out, "  lea rsi, [rel DUMP_BUFFER]\n");
+  write_all(out, "  mov rdx, rdi\n");
+  write_all(out, "  sub rdx, rsi\n");
+  write_all(out, "  mov rax, 0x2000004 ; write\n");
+  write_all(out, "  mov rdi, 2         ; stderr\n");
+  write_all(out, "  syscall\n");
+  write_all(out, "  jmp rbx\n");
+}
+
+/// Emit code writing the number in `rax` in decimal to `rdi`, leaving `rdi` just past the last
+/// digit. `rcx`, `rdx` and `r11` are used along the way, `name` keeps the labels apart.
+fn emit_decimal<W: Write>(out: &mut W, name: &str) {
+  // Count the digits first, so they can be written from the last one back
+  write_all(out, "  mov r11, rax\n");
+  write_all(out, "  mov ecx, 10\n");
+  write_all(out, &format!("DUMP_{}_COUNT:\n", name));
+  write_all(out, "  xor edx, edx\n");
+  write_all(out, "  div rcx\n");
+  write_all(out, "  inc rdi\n");
+  write_all(out, "  test rax, rax\n");
+  write_all(out, &format!("  jnz DUMP_{}_COUNT\n", name));
+  write_all(out, "  mov rax, r11\n");
+  write_all(out, "  mov r11, rdi\n");
+  write_all(out, &format!("DUMP_{}_DIGIT:\n", name));
+  write_all(out, "  xor edx, edx\n");
+  write_all(out, "  div rcx\n");
+  write_all(out, "  add dl, '0'\n");
+  write_all(out, "  dec rdi\n");
+  write_all(out, "  mov [rdi], dl\n");
+  write_all(out, "  test rax, rax\n");
+  write_all(out, &format!("  jnz DUMP_{}_DIGIT\n", name));
+  write_all(out, "  mov rdi, r11\n");
+}
+
+fn emit_data<W: Write>(out: &mut W, state: &EmitState) {
+  if state.constants.is_empty() && state.vectors.is_empty() && state.dumps.is_empty() {
+    return;
+  }
+  write_all(out, "section .data\n");
+  // Vector constants are read straight from memory by `paddb`, which needs them to be aligned
+  if !state.vectors.is_empty() {
+    write_all(out, "align 32\n");
+  }
+  for (index, bytes) in state.vectors.iter().enumerate() {
+    let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+    write_all(out, &format!("VEC{}: db {}\n", index, bytes.join(", ")));
+  }
+  for (index, bytes) in state.constants.iter().enumerate() {
+    let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+    write_all(out, &format!("CONST{}: db {}\n", index, bytes.join(", ")));
+  }
+  for (index, heading) in state.dumps.iter().enumerate() {
+    let bytes: Vec<String> = heading.iter().map(|byte| byte.to_string()).collect();
+    write_all(out, &format!("DUMP_AT{}: db {}\n", index, bytes.join(", ")));
+  }
+  if !state.dumps.is_empty() {
+    write_all(out, &format!("DUMP_CELLS: db \"{}\"\n", DUMP_CELLS));
+  }
+}
+
+fn emit_bss<W: Write>(out: &mut W, state: &EmitState) {
   write_all(out, "section .bss\n");
-  write_all(out, "tape: resq 10000\n");
+  write_all(out, &format!("tape: resb {}\n", TAPE_SIZE));
+  if let Some(heading) = state.dumps.iter().map(Vec::len).max() {
+    // The heading, up to 20 digits for the pointer and the first cell, " [255]" at most for each
+    // cell and a newline
+    let size = heading + 20 + DUMP_CELLS.len() + 20 + 1 + (2 * DUMP_WINDOW + 1) * 6 + 1;
+    write_all(out, &format!("DUMP_BUFFER: resb {}\n", size));
+  }
 }
 
 fn write_all<W: Write>(out: &mut W, to_write: &str) {
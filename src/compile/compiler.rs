@@ -5,22 +5,35 @@
 
 use parse::ast::Program;
 use parse::ast::Expr;
+use parse::Span;
 use super::bytecode::{ByteProgram, ByteCode};
 use std::collections::VecDeque;
 use std::fmt;
 
-#[derive(Debug, Clone)]
-pub struct Label(String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label(usize);
+
+impl Label {
+  pub fn new(id: usize) -> Self {
+    Label(id)
+  }
+
+  /// The id of the loop this label belongs to, loops are numbered in the order their '[' appears
+  /// in the source.
+  pub fn id(&self) -> usize {
+    self.0
+  }
+}
 
 impl fmt::Display for Label {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", self.0)
+    write!(f, "LOOP{}", self.0)
   }
 }
 
 pub trait Compiler {
   fn compile_program(&mut self, program: &Program) -> ByteProgram;
-  fn compile_expr(&mut self, expr: &Expr) -> VecDeque<ByteCode>;
+  fn compile_expr(&mut self, expr: &Expr, span: Span) -> VecDeque<ByteCode>;
 }
 
 pub struct SimpleCompiler {
@@ -36,7 +49,7 @@ impl Compiler for SimpleCompiler {
     // TODO: Drain stdin
   }
 
-  fn compile_expr(&mut self, expr: &Expr) -> VecDeque<ByteCode> {
+  fn compile_expr(&mut self, expr: &Expr, span: Span) -> VecDeque<ByteCode> {
     let mut byte_code = VecDeque::new();
     match *expr {
       Expr::MoveRight => {  //println!("  inc rsp"),
@@ -67,6 +80,10 @@ impl Compiler for SimpleCompiler {
       },                                //"  mov rsi, rsp\n",
                                       //"  mov rdx, 1\n",
                                       //"  syscall")),
+      Expr::Debug => {
+        byte_code.push_back(ByteCode::Dump(span));
+        byte_code
+      },
       Expr::Loop(ref inner) => {
         let loop_label = self.next_loop_label();
         // TODO: Come back and fix this, I think we need to pass in a compiler kind of like the
@@ -95,7 +112,7 @@ impl SimpleCompiler {
   }
 
   fn next_loop_label(&mut self) -> Label {
-    let label = Label(format!("LOOP{}", self.loop_count));
+    let label = Label(self.loop_count);
     self.loop_count += 1;
     label
   }
@@ -0,0 +1,302 @@
+// compile/evaluator.rs
+// Rustration
+
+use super::bytecode::{ByteCode, TAPE_SIZE};
+
+/// The reason an `Evaluator` stopped running code.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+  /// Every instruction that was given has been run
+  Finished,
+
+  /// An `Exit` instruction was reached, the program is done
+  Exit,
+
+  /// A `Read` instruction was reached, we can't know what the program will do from here
+  Input,
+
+  /// A `Dump` instruction was reached, it has to show the tape as the program is running
+  Dump,
+
+  /// The step budget ran out
+  OutOfBudget,
+
+  /// The data pointer would have left the tape
+  OutOfBounds,
+}
+
+/// Runs byte code at compile time, collecting its output instead of writing it anywhere.
+pub struct Evaluator {
+  tape: Vec<u8>,
+  pointer: usize,
+  output: Vec<u8>,
+  budget: usize,
+  // The index and old value of every cell written since the last snapshot
+  undo: Vec<(usize, u8)>,
+}
+
+/// Everything needed to roll an `Evaluator` back to an earlier state, other than the writes it
+/// records after the snapshot is taken.
+pub struct Snapshot {
+  tape_len: usize,
+  pointer: usize,
+  output_len: usize,
+  budget: usize,
+}
+
+impl Evaluator {
+  /// Create a new evaluator with a fresh tape that will run at most `budget` instructions.
+  pub fn new(budget: usize) -> Self {
+    Evaluator {
+      tape: vec![0; 1],
+      pointer: 0,
+      output: Vec::new(),
+      budget,
+      undo: Vec::new(),
+    }
+  }
+
+  /// The contents of the tape, every cell past the end is zero.
+  pub fn tape(&self) -> &[u8] {
+    &self.tape
+  }
+
+  /// The position of the data pointer on the tape.
+  pub fn pointer(&self) -> usize {
+    self.pointer
+  }
+
+  /// Everything the code run so far has written.
+  pub fn output(&self) -> &[u8] {
+    &self.output
+  }
+
+  /// Start recording writes so that the evaluator can be rolled back to where it is now.
+  ///
+  /// Writes are only recorded since the last snapshot, so that's the only one that can be restored.
+  pub fn snapshot(&mut self) -> Snapshot {
+    self.undo.clear();
+    Snapshot {
+      tape_len: self.tape.len(),
+      pointer: self.pointer,
+      output_len: self.output.len(),
+      budget: self.budget,
+    }
+  }
+
+  pub fn restore(&mut self, snapshot: Snapshot) {
+    while let Some((index, cell)) = self.undo.pop() {
+      self.tape[index] = cell;
+    }
+    self.tape.truncate(snapshot.tape_len);
+    self.pointer = snapshot.pointer;
+    self.output.truncate(snapshot.output_len);
+    self.budget = snapshot.budget;
+  }
+
+  /// Run a sequence of instructions, every `Jump` in `code` must have its matching `JumpNotZero`
+  /// in `code` as well.
+  ///
+  /// If this stops for any reason other than `Finished` or `Exit` the evaluator is left part way
+  /// through the code and should be restored from a snapshot.
+  pub fn run(&mut self, code: &[ByteCode]) -> Stop {
+    let targets = match_jumps(code);
+    let mut pc = 0;
+    while pc < code.len() {
+      if self.budget == 0 {
+        return Stop::OutOfBudget;
+      }
+      self.budget -= 1;
+      match code[pc] {
+        ByteCode::Add(num) => { self.add(0, num); },
+        ByteCode::Sub(num) => { self.add(0, -num); },
+        ByteCode::AddAt(offset, num) => if !self.add(offset, num) { return Stop::OutOfBounds },
+        ByteCode::SubAt(offset, num) => if !self.add(offset, -num) { return Stop::OutOfBounds },
+        ByteCode::AddVector(offset, ref bytes) => {
+          for (lane, &byte) in bytes.iter().enumerate() {
+            if !self.add(offset + lane as isize, byte as isize) {
+              return Stop::OutOfBounds;
+            }
+          }
+        },
+        ByteCode::MoveRight(num) => if !self.move_by(num) { return Stop::OutOfBounds },
+        ByteCode::MoveLeft(num) => if !self.move_by(-num) { return Stop::OutOfBounds },
+        ByteCode::Clear => self.set(self.pointer, 0),
+        ByteCode::ScanRight(num) => if let Some(stop) = self.scan(num) { return stop },
+        ByteCode::ScanLeft(num) => if let Some(stop) = self.scan(-num) { return stop },
+        ByteCode::MultiplyAdd(offset, factor) => {
+          let value = self.tape[self.pointer] as isize;
+          if !self.add(offset, value * factor) {
+            return Stop::OutOfBounds;
+          }
+        },
+        ByteCode::Read => return Stop::Input,
+        ByteCode::Dump(_) => return Stop::Dump,
+        ByteCode::Write => self.output.push(self.tape[self.pointer]),
+        ByteCode::WriteBytes(ref bytes) => self.output.extend_from_slice(bytes),
+        ByteCode::Jump(_) => {
+          pc = targets[pc];
+          continue;
+        },
+        ByteCode::JumpNotZero(_) => if self.tape[self.pointer] != 0 {
+          pc = targets[pc];
+        },
+        ByteCode::ExitIfZero(_) => if self.tape[self.pointer] == 0 {
+          pc = targets[pc];
+          continue;
+        },
+        ByteCode::Exit => return Stop::Exit,
+      }
+      pc += 1;
+    }
+    Stop::Finished
+  }
+
+  /// Move the data pointer `step` cells at a time until it lands on a zero cell, spending budget
+  /// on every step.
+  fn scan(&mut self, step: isize) -> Option<Stop> {
+    while self.tape[self.pointer] != 0 {
+      if self.budget == 0 {
+        return Some(Stop::OutOfBudget);
+      }
+      self.budget -= 1;
+      if !self.move_by(step) {
+        return Some(Stop::OutOfBounds);
+      }
+    }
+    None
+  }
+
+  /// Add `num` to the cell at `offset` from the data pointer, returning false if that cell is off
+  /// the tape.
+  fn add(&mut self, offset: isize, num: isize) -> bool {
+    match self.cell_index(offset) {
+      Some(index) => {
+        let cell = self.tape[index].wrapping_add(num as u8);
+        self.set(index, cell);
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// Write `cell` to the tape at `index`, keeping the old value so it can be undone.
+  fn set(&mut self, index: usize, cell: u8) {
+    self.undo.push((index, self.tape[index]));
+    self.tape[index] = cell;
+  }
+
+  /// Move the data pointer by `num` cells, returning false if that would move it off the tape.
+  fn move_by(&mut self, num: isize) -> bool {
+    match self.cell_index(num) {
+      Some(index) => {
+        self.pointer = index;
+        true
+      },
+      None => false,
+    }
+  }
+
+  fn cell_index(&mut self, offset: isize) -> Option<usize> {
+    let index = self.pointer as isize + offset;
+    if index < 0 || index >= TAPE_SIZE as isize {
+      return None;
+    }
+    let index = index as usize;
+    if index >= self.tape.len() {
+      self.tape.resize(index + 1, 0);
+    }
+    Some(index)
+  }
+}
+
+/// Find the index of the matching instruction for every `Jump` and `JumpNotZero` in `code`.
+///
+/// A `Jump` and an `ExitIfZero` jump to the check at the `JumpNotZero` of their loop, a
+/// `JumpNotZero` jumps back to the first instruction of the loop body, right after its `Jump`.
+/// Other indices are left as zero.
+fn match_jumps(code: &[ByteCode]) -> Vec<usize> {
+  let mut targets = vec![0; code.len()];
+  let mut starts = Vec::new();
+  let mut exits = Vec::new();
+  for (index, op) in code.iter().enumerate() {
+    match *op {
+      ByteCode::Jump(_) => starts.push(index),
+      ByteCode::ExitIfZero(_) => exits.push((starts.len(), index)),
+      ByteCode::JumpNotZero(_) => {
+        while exits.last().is_some_and(|&(depth, _)| depth == starts.len()) {
+          let (_, exit) = exits.pop().unwrap();
+          targets[exit] = index;
+        }
+        let start = starts.pop().expect("unmatched JumpNotZero");
+        targets[start] = index;
+        targets[index] = start;
+      },
+      _ => {},
+    }
+  }
+  targets
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use compile::{Compiler, SimpleCompiler};
+  use compile::compiler::Label;
+  use parse::RawParser;
+  use std::collections::VecDeque;
+
+  fn evaluate(source: &str, budget: usize) -> (Evaluator, Stop) {
+    let program = RawParser::new(source.as_bytes().to_vec()).parse().unwrap();
+    let byte_code: VecDeque<ByteCode> = SimpleCompiler::new().compile_program(&program).into();
+    let code: Vec<ByteCode> = byte_code.into_iter().collect();
+    let mut evaluator = Evaluator::new(budget);
+    let stop = evaluator.run(&code);
+    (evaluator, stop)
+  }
+
+  #[test]
+  fn evaluate_output() {
+    let (evaluator, stop) = evaluate("++++++++[>++++++++<-]>+.+.", 1000);
+    assert_eq!(stop, Stop::Exit);
+    assert_eq!(evaluator.output(), b"AB");
+    assert_eq!(evaluator.pointer(), 1);
+    assert_eq!(evaluator.tape(), &[0, 66]);
+  }
+
+  #[test]
+  fn evaluate_stops() {
+    assert_eq!(evaluate("+.,.", 1000).1, Stop::Input);
+    assert_eq!(evaluate("+[]", 1000).1, Stop::OutOfBudget);
+    assert_eq!(evaluate("<", 1000).1, Stop::OutOfBounds);
+  }
+
+  #[test]
+  fn evaluate_exit_if_zero() {
+    // `+++[->+<]` unrolled once
+    let label = Label::new(0);
+    let body = [ByteCode::Sub(1), ByteCode::MoveRight(1), ByteCode::Add(1), ByteCode::MoveLeft(1)];
+    let mut code = vec![ByteCode::Add(3), ByteCode::Jump(label.clone())];
+    code.extend(body.iter().cloned());
+    code.push(ByteCode::ExitIfZero(label.clone()));
+    code.extend(body.iter().cloned());
+    code.push(ByteCode::JumpNotZero(label));
+    code.push(ByteCode::Exit);
+
+    let mut evaluator = Evaluator::new(1000);
+    assert_eq!(evaluator.run(&code), Stop::Exit);
+    assert_eq!(evaluator.tape(), &[0, 3]);
+  }
+
+  #[test]
+  fn restore_snapshot() {
+    let (mut evaluator, _) = evaluate("+++.>+", 1000);
+    let snapshot = evaluator.snapshot();
+    let code = [ByteCode::Write, ByteCode::Clear, ByteCode::Add(4), ByteCode::AddAt(2, 5), ByteCode::MoveLeft(1)];
+    evaluator.run(&code);
+    evaluator.restore(snapshot);
+    assert_eq!(evaluator.output(), &[3]);
+    assert_eq!(evaluator.tape(), &[3, 1]);
+    assert_eq!(evaluator.pointer(), 1);
+  }
+}
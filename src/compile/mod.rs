@@ -6,8 +6,12 @@
 mod compiler;
 mod bytecode;
 mod optimizer;
+mod evaluator;
+mod pass;
 
 pub use self::compiler::Compiler;
 pub use self::compiler::SimpleCompiler;
-pub use self::bytecode::{ByteCode, ByteProgram};
-pub use self::optimizer::Optimizer;
+pub use self::bytecode::ByteCode;
+pub use self::optimizer::{Optimizer, Idiom, Step, recognize_idiom};
+pub use self::optimizer::{DEFAULT_OPT_LEVEL, DEFAULT_EVAL_BUDGET, DEFAULT_VECTOR_WIDTH};
+pub use self::pass::{Pass, PASSES};
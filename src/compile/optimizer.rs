@@ -3,35 +3,242 @@
 //
 // Created by Daniel Seitz on 1/12/17
 
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::mem;
 use super::bytecode::{ByteCode, ByteProgram};
+use super::evaluator::{Evaluator, Stop};
+use super::pass::Pass;
+use super::compiler::Label;
+use profile::Profile;
 
+/// The default number of instructions to run at compile time when partially evaluating a program.
+pub const DEFAULT_EVAL_BUDGET: usize = 10_000_000;
+
+/// The default optimization level, used by `-O` on its own.
+pub const DEFAULT_OPT_LEVEL: u8 = 2;
+
+/// The number of iterations a loop needs in a profile before it's worth unrolling.
+const HOT_ITERATIONS: u64 = 1000;
+
+/// The number of copies of a loop body an unrolled loop has.
+const UNROLL_FACTOR: usize = 4;
+
+/// The largest loop body we're willing to unroll.
+const MAX_UNROLL_BODY: usize = 16;
+
+/// The default width in bytes of the vector adds the vectorize pass emits.
+///
+/// Every x86-64 CPU can add 16 bytes at a time, adding 32 needs AVX2.
+pub const DEFAULT_VECTOR_WIDTH: usize = 16;
+
+/// The fewest adjacent cells worth updating with a vector add.
+const MIN_VECTOR_WIDTH: usize = 16;
+
+/// Runs a set of optimization passes over a program.
 pub struct Optimizer {
   program: ByteProgram,
+  passes: Vec<Pass>,
+  print_after: Vec<Pass>,
+  eval_budget: usize,
+  vector_width: usize,
+  profile: Option<Profile>,
 }
 
 impl Optimizer {
+  /// Create an optimizer that runs the passes of the default optimization level.
   pub fn new(program: ByteProgram) -> Self {
     Optimizer {
-      program: program,
+      program,
+      passes: Pass::preset(DEFAULT_OPT_LEVEL),
+      print_after: Vec::new(),
+      eval_budget: DEFAULT_EVAL_BUDGET,
+      vector_width: DEFAULT_VECTOR_WIDTH,
+      profile: None,
     }
   }
 
+  /// Run exactly the passes of the given optimization level.
+  pub fn level(mut self, level: u8) -> Self {
+    self.passes = Pass::preset(level);
+    self
+  }
+
+  /// Run `pass` on top of the passes that are already enabled.
+  pub fn enable(mut self, pass: Pass) -> Self {
+    if !self.passes.contains(&pass) {
+      self.passes.push(pass);
+      self.passes.sort();
+    }
+    self
+  }
+
+  /// Don't run `pass`, even if the optimization level includes it.
+  pub fn disable(mut self, pass: Pass) -> Self {
+    self.passes.retain(|&enabled| enabled != pass);
+    self
+  }
+
+  /// Print the program's byte code after `pass` runs.
+  pub fn print_after(mut self, pass: Pass) -> Self {
+    self.print_after.push(pass);
+    self
+  }
+
+  /// The maximum number of instructions to run at compile time during partial evaluation.
+  pub fn eval_budget(mut self, budget: usize) -> Self {
+    self.eval_budget = budget;
+    self
+  }
+
+  /// The width in bytes of the widest vector add the vectorize pass emits, either 16 or 32.
+  pub fn vector_width(mut self, width: usize) -> Self {
+    self.vector_width = width;
+    self
+  }
+
+  /// Guide the profile guided passes with a profile of the program being optimized.
+  pub fn profile(mut self, profile: Profile) -> Self {
+    self.profile = Some(profile);
+    self
+  }
+
   pub fn optimize(self) -> ByteProgram {
-    ByteProgram::from(optimize(self.program.into()))
+    self.optimize_with_report().0
+  }
+
+  /// Optimize the program, keeping track of what each pass did to it.
+  pub fn optimize_with_report(self) -> (ByteProgram, OptReport) {
+    let mut byte_code: VecDeque<ByteCode> = self.program.into();
+    let mut report = OptReport {
+      before: byte_code.len(),
+      after: byte_code.len(),
+      passes: Vec::new(),
+    };
+    let mut cold_loops = Vec::new();
+    for &pass in self.passes.iter() {
+      let mut stats = PassStats::new(pass, byte_code.len());
+      byte_code = match pass {
+        Pass::Coalesce => coalesce(byte_code, &mut stats),
+        Pass::LoopIdioms => optimize_loops(byte_code, &mut stats),
+        Pass::DeadLoops => remove_dead_loops(byte_code),
+        Pass::Unroll => unroll_hot_loops(byte_code, self.profile.as_ref(), &mut stats),
+        Pass::FoldOffsets => fold_offsets(byte_code, &mut stats),
+        Pass::PartialEval => partially_evaluate(byte_code, self.eval_budget, &mut stats),
+        Pass::Vectorize => vectorize(byte_code, self.vector_width, &mut stats),
+        Pass::ColdLayout => {
+          cold_loops = find_cold_loops(&byte_code, self.profile.as_ref());
+          stats.rewritten = cold_loops.len();
+          byte_code
+        },
+      };
+      stats.after = byte_code.len();
+      report.passes.push(stats);
+      if self.print_after.contains(&pass) {
+        println!("; Byte code after {}", pass);
+        for op in byte_code.iter() {
+          println!("{}", op);
+        }
+      }
+    }
+    report.after = byte_code.len();
+    let mut program = ByteProgram::from(byte_code);
+    for label in cold_loops {
+      program.mark_cold(label);
+    }
+    (program, report)
   }
 }
 
-fn optimize(mut byte_code: VecDeque<ByteCode>) -> VecDeque<ByteCode> {
+/// What a single optimization pass did to a program.
+#[derive(Debug)]
+pub struct PassStats {
+  pub pass: Pass,
+
+  /// The number of instructions before the pass ran
+  pub before: usize,
+
+  /// The number of instructions after the pass ran
+  pub after: usize,
+
+  /// The number of instructions the pass emitted in place of other instructions
+  pub rewritten: usize,
+
+  /// The number of loops recognized as a clear loop
+  pub clear: usize,
+
+  /// The number of loops recognized as a scan loop
+  pub scan: usize,
+
+  /// The number of loops recognized as a multiply loop
+  pub multiply: usize,
+
+  /// The number of loops that are left as they are
+  pub general: usize,
+}
+
+impl PassStats {
+  /// Start keeping track of what `pass` does to a program of `before` instructions.
+  fn new(pass: Pass, before: usize) -> Self {
+    PassStats {
+      pass,
+      before,
+      after: 0,
+      rewritten: 0,
+      clear: 0,
+      scan: 0,
+      multiply: 0,
+      general: 0,
+    }
+  }
+
+  /// The number of instructions the pass got rid of.
+  pub fn removed(&self) -> usize {
+    self.before.saturating_sub(self.after)
+  }
+}
+
+/// What every pass did to a program, along with its size before and after optimizing.
+#[derive(Debug)]
+pub struct OptReport {
+  pub before: usize,
+  pub after: usize,
+  pub passes: Vec<PassStats>,
+}
+
+impl fmt::Display for OptReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{:<14}{:>8}{:>8}{:>9}{:>11}{:>7}{:>6}{:>10}{:>9}",
+             "Pass", "Before", "After", "Removed", "Rewritten", "Clear", "Scan", "Multiply", "General")?;
+    for stats in self.passes.iter() {
+      writeln!(f, "{:<14}{:>8}{:>8}{:>9}{:>11}{:>7}{:>6}{:>10}{:>9}",
+               stats.pass.name(), stats.before, stats.after, stats.removed(), stats.rewritten,
+               stats.clear, stats.scan, stats.multiply, stats.general)?;
+    }
+    write!(f, "Instructions before optimizing: {}, after optimizing: {}", self.before, self.after)
+  }
+}
+
+fn coalesce(mut byte_code: VecDeque<ByteCode>, stats: &mut PassStats) -> VecDeque<ByteCode> {
   let mut optimized = VecDeque::with_capacity(byte_code.len());
   while let Some(op) = byte_code.pop_front() {
-    match op {
-      ByteCode::Add(num) => optimized.append(&mut optimize_add(&mut byte_code, num)),
-      ByteCode::Sub(num) => optimized.append(&mut optimize_add(&mut byte_code, -num)),
-      ByteCode::MoveRight(num) => optimized.append(&mut optimize_move(&mut byte_code, num)),
-      ByteCode::MoveLeft(num) => optimized.append(&mut optimize_move(&mut byte_code, -num)),
-      _ => optimized.push_back(op),
+    let before = byte_code.len();
+    let mut coalesced = match op {
+      ByteCode::Add(num) => optimize_add(&mut byte_code, num),
+      ByteCode::Sub(num) => optimize_add(&mut byte_code, -num),
+      ByteCode::MoveRight(num) => optimize_move(&mut byte_code, num),
+      ByteCode::MoveLeft(num) => optimize_move(&mut byte_code, -num),
+      _ => {
+        optimized.push_back(op);
+        continue;
+      },
+    };
+    // Anything that absorbed a following instruction was rewritten
+    if byte_code.len() < before {
+      stats.rewritten += coalesced.len();
     }
+    optimized.append(&mut coalesced);
   }
   optimized
 }
@@ -77,3 +284,661 @@ fn optimize_move(byte_code: &mut VecDeque<ByteCode>, mut sum: isize) -> VecDeque
   }
   optimized
 }
+
+/// A loop whose effect can be computed without iterating over its body.
+#[derive(Debug, PartialEq)]
+pub enum Idiom {
+  /// A loop like `[-]` or `[+++]` that adds an odd step to the current cell until it wraps around
+  /// to zero
+  Clear(isize),
+
+  /// A loop like `[>]` or `[<<]` that moves the data pointer by a fixed step until it finds a
+  /// zero cell
+  Scan(isize),
+
+  /// A balanced loop like `[->+>++<<]` that adds the current cell times a factor to the cell at
+  /// each `(offset, factor)` pair and then clears the current cell
+  Multiply(Vec<(isize, isize)>),
+}
+
+/// The effect of a single operation in a loop body, as far as recognizing idioms is concerned.
+pub enum Step {
+  /// Move the data pointer by the given number of cells
+  Move(isize),
+
+  /// Add the given amount to the cell under the data pointer
+  Add(isize),
+
+  /// Anything else, these loops are never idioms
+  Other,
+}
+
+/// Figure out if a loop body with no nested loops is one of the idioms we know how to execute
+/// without iterating.
+pub fn recognize_idiom<I: IntoIterator<Item = Step>>(body: I) -> Option<Idiom> {
+  let mut cells = BTreeMap::new();
+  let mut offset: isize = 0;
+  let mut lowest = 0;
+  let mut monotonic = true;
+  for step in body {
+    match step {
+      Step::Move(num) => {
+        if offset.signum() * num.signum() < 0 {
+          monotonic = false;
+        }
+        offset += num;
+        lowest = cmp::min(lowest, offset);
+      },
+      Step::Add(num) => *cells.entry(offset).or_insert(0) += num,
+      Step::Other => return None,
+    }
+  }
+  cells.retain(|_, sum| *sum != 0);
+
+  if offset != 0 {
+    return if cells.is_empty() && monotonic { Some(Idiom::Scan(offset)) } else { None };
+  }
+  let entry = cells.remove(&0);
+  // Don't wander further left than the cells we actually touch, the interpreter clamps the data
+  // pointer at the start of the tape which would break the balance of the loop
+  let reach = cells.keys().next().map_or(0, |&first| cmp::min(first, 0));
+  if lowest < reach {
+    return None;
+  }
+  match entry {
+    // An odd step is guaranteed to wrap around to zero eventually
+    Some(sum) if cells.is_empty() && sum % 2 != 0 => Some(Idiom::Clear(sum)),
+    Some(-1) => Some(Idiom::Multiply(cells.into_iter().collect())),
+    _ => None,
+  }
+}
+
+/// Replace innermost loops that match a well known idiom with straight line code.
+fn optimize_loops(byte_code: VecDeque<ByteCode>, stats: &mut PassStats) -> VecDeque<ByteCode> {
+  let mut optimized = VecDeque::with_capacity(byte_code.len());
+  let mut loop_start = None;
+  for op in byte_code {
+    match op {
+      ByteCode::Jump(_) => loop_start = Some(optimized.len()),
+      ByteCode::JumpNotZero(_) => {
+        if let Some(start) = loop_start.take() {
+          let idiom = {
+            let body = optimized.iter().skip(start + 1).map(|op| match *op {
+              ByteCode::Add(num) => Step::Add(num),
+              ByteCode::Sub(num) => Step::Add(-num),
+              ByteCode::MoveRight(num) => Step::Move(num),
+              ByteCode::MoveLeft(num) => Step::Move(-num),
+              _ => Step::Other,
+            });
+            recognize_idiom(body)
+          };
+          if let Some(idiom) = idiom {
+            match idiom {
+              Idiom::Clear(_) => stats.clear += 1,
+              Idiom::Scan(_) => stats.scan += 1,
+              Idiom::Multiply(_) => stats.multiply += 1,
+            }
+            let mut lowered = lower_idiom(idiom);
+            stats.rewritten += lowered.len();
+            optimized.truncate(start);
+            optimized.append(&mut lowered);
+            continue;
+          }
+        }
+        stats.general += 1;
+      },
+      _ => {},
+    }
+    optimized.push_back(op);
+  }
+  optimized
+}
+
+fn lower_idiom(idiom: Idiom) -> VecDeque<ByteCode> {
+  let mut byte_code = VecDeque::new();
+  match idiom {
+    Idiom::Clear(_) => byte_code.push_back(ByteCode::Clear),
+    Idiom::Scan(step) if step > 0 => byte_code.push_back(ByteCode::ScanRight(step)),
+    Idiom::Scan(step) => byte_code.push_back(ByteCode::ScanLeft(-step)),
+    Idiom::Multiply(targets) => {
+      for (offset, factor) in targets {
+        byte_code.push_back(ByteCode::MultiplyAdd(offset, factor));
+      }
+      byte_code.push_back(ByteCode::Clear);
+    },
+  }
+  byte_code
+}
+
+/// Remove loops that can never be entered because the cell under the data pointer is known to be
+/// zero.
+///
+/// Every cell is zero at the start of the program and the current cell is zero right after any
+/// loop exits, so comment loops at the start of a file and loops directly following another loop
+/// never run. Idioms that only have an effect on a non-zero cell are removed in the same way.
+fn remove_dead_loops(mut byte_code: VecDeque<ByteCode>) -> VecDeque<ByteCode> {
+  let mut optimized = VecDeque::with_capacity(byte_code.len());
+  // No cell has been written to yet, so every cell is zero
+  let mut pristine = true;
+  let mut current_zero = true;
+  while let Some(op) = byte_code.pop_front() {
+    match op {
+      ByteCode::Jump(_) if current_zero => {
+        skip_loop(&mut byte_code);
+        continue;
+      },
+      ByteCode::Clear | ByteCode::ScanRight(_) | ByteCode::ScanLeft(_) | ByteCode::MultiplyAdd(..)
+        if current_zero => continue,
+      ByteCode::Jump(_) | ByteCode::ExitIfZero(_) | ByteCode::Add(_) | ByteCode::Sub(_) |
+        ByteCode::AddVector(..) | ByteCode::Read => {
+        pristine = false;
+        current_zero = false;
+      },
+      ByteCode::MoveRight(_) | ByteCode::MoveLeft(_) => current_zero = pristine,
+      ByteCode::AddAt(..) | ByteCode::SubAt(..) | ByteCode::MultiplyAdd(..) => pristine = false,
+      ByteCode::Clear | ByteCode::ScanRight(_) | ByteCode::ScanLeft(_) |
+        ByteCode::JumpNotZero(_) => current_zero = true,
+      ByteCode::Write | ByteCode::WriteBytes(_) | ByteCode::Dump(_) | ByteCode::Exit => {},
+    }
+    optimized.push_back(op);
+  }
+  optimized
+}
+
+/// Drop everything up to and including the `JumpNotZero` matching a `Jump` that was just removed.
+fn skip_loop(byte_code: &mut VecDeque<ByteCode>) {
+  let mut depth = 1;
+  while let Some(op) = byte_code.pop_front() {
+    match op {
+      ByteCode::Jump(_) => depth += 1,
+      ByteCode::JumpNotZero(_) => depth -= 1,
+      _ => {},
+    }
+    if depth == 0 {
+      break;
+    }
+  }
+}
+
+/// Unroll innermost loops that ran for many iterations in the profile.
+///
+/// The body of the loop is repeated `UNROLL_FACTOR` times, with a check to leave the loop between
+/// each copy, so fewer jumps are taken per iteration.
+fn unroll_hot_loops(byte_code: VecDeque<ByteCode>, profile: Option<&Profile>, stats: &mut PassStats)
+  -> VecDeque<ByteCode> {
+  let profile = match profile {
+    Some(profile) => profile,
+    None => return byte_code,
+  };
+  let mut optimized = VecDeque::with_capacity(byte_code.len());
+  let mut loop_start = None;
+  for op in byte_code {
+    match op {
+      ByteCode::Jump(_) => loop_start = Some(optimized.len()),
+      ByteCode::JumpNotZero(ref label) => if let Some(start) = loop_start.take() {
+        let counts = profile.loop_counts(label.id());
+        let body_len = optimized.len() - start - 1;
+        // Only bother if the loop usually runs for a few iterations each time it's reached
+        if counts.iterations >= HOT_ITERATIONS && counts.iterations >= 2 * counts.entries &&
+           body_len <= MAX_UNROLL_BODY {
+          let body: Vec<ByteCode> = optimized.iter().skip(start + 1).cloned().collect();
+          for _ in 1..UNROLL_FACTOR {
+            optimized.push_back(ByteCode::ExitIfZero(label.clone()));
+            optimized.extend(body.iter().cloned());
+          }
+          stats.rewritten += (UNROLL_FACTOR - 1) * (body_len + 1);
+        }
+      },
+      _ => {},
+    }
+    optimized.push_back(op);
+  }
+  optimized
+}
+
+/// Find the labels of loops that were never reached in the profile.
+fn find_cold_loops(byte_code: &VecDeque<ByteCode>, profile: Option<&Profile>) -> Vec<Label> {
+  let profile = match profile {
+    Some(profile) => profile,
+    None => return Vec::new(),
+  };
+  byte_code.iter().filter_map(|op| match *op {
+    ByteCode::Jump(ref label) if profile.loop_counts(label.id()).entries == 0 => Some(label.clone()),
+    _ => None,
+  }).collect()
+}
+
+/// Defer moving the data pointer within a basic block.
+///
+/// Instead of moving the data pointer before every `Add` or `Sub` we keep track of where it should
+/// be and address cells by their offset from the real pointer, only moving it once before any
+/// I/O, loop or other instruction that needs the pointer to be up to date.
+fn fold_offsets(byte_code: VecDeque<ByteCode>, stats: &mut PassStats) -> VecDeque<ByteCode> {
+  let mut optimized = VecDeque::with_capacity(byte_code.len());
+  let mut offset = 0;
+  for op in byte_code {
+    match op {
+      ByteCode::MoveRight(num) => offset += num,
+      ByteCode::MoveLeft(num) => offset -= num,
+      ByteCode::Add(num) if offset != 0 => {
+        stats.rewritten += 1;
+        optimized.push_back(ByteCode::AddAt(offset, num));
+      },
+      ByteCode::Sub(num) if offset != 0 => {
+        stats.rewritten += 1;
+        optimized.push_back(ByteCode::SubAt(offset, num));
+      },
+      ByteCode::Add(_) | ByteCode::Sub(_) => optimized.push_back(op),
+      _ => {
+        if offset > 0 {
+          optimized.push_back(ByteCode::MoveRight(offset));
+        }
+        else if offset < 0 {
+          optimized.push_back(ByteCode::MoveLeft(-offset));
+        }
+        offset = 0;
+        optimized.push_back(op);
+      },
+    }
+  }
+  optimized
+}
+
+/// Run as much of the program as possible at compile time.
+///
+/// The program is run one top level instruction or loop at a time until it reads input, dumps
+/// the tape, runs out of budget or exits. Everything it wrote up to that point is replaced with a single constant
+/// write, followed by code to set up the tape the way it was left and the rest of the program.
+fn partially_evaluate(byte_code: VecDeque<ByteCode>, budget: usize, stats: &mut PassStats)
+  -> VecDeque<ByteCode> {
+  let code: Vec<ByteCode> = byte_code.into_iter().collect();
+  let mut evaluator = Evaluator::new(budget);
+  let mut resume = 0;
+  let mut exited = false;
+  while resume < code.len() && !exited {
+    let end = match code[resume] {
+      ByteCode::Jump(_) => resume + loop_length(&code[resume..]),
+      _ => resume + 1,
+    };
+    let snapshot = evaluator.snapshot();
+    match evaluator.run(&code[resume..end]) {
+      Stop::Finished => resume = end,
+      Stop::Exit => exited = true,
+      Stop::Input | Stop::Dump | Stop::OutOfBudget | Stop::OutOfBounds => {
+        evaluator.restore(snapshot);
+        break;
+      },
+    }
+  }
+  if resume == 0 {
+    return code.into_iter().collect();
+  }
+
+  let mut optimized = VecDeque::new();
+  if !evaluator.output().is_empty() {
+    optimized.push_back(ByteCode::WriteBytes(evaluator.output().to_vec()));
+  }
+  if exited {
+    optimized.push_back(ByteCode::Exit);
+    stats.rewritten = optimized.len();
+    return optimized;
+  }
+  for (index, &cell) in evaluator.tape().iter().enumerate() {
+    match (index, cell) {
+      (_, 0) => {},
+      (0, cell) => optimized.push_back(ByteCode::Add(cell as isize)),
+      (index, cell) => optimized.push_back(ByteCode::AddAt(index as isize, cell as isize)),
+    }
+  }
+  if evaluator.pointer() > 0 {
+    optimized.push_back(ByteCode::MoveRight(evaluator.pointer() as isize));
+  }
+  stats.rewritten = optimized.len();
+  optimized.extend(code.into_iter().skip(resume));
+  optimized
+}
+
+/// Pack updates to runs of adjacent cells into vector adds.
+///
+/// Updates to cells between any two other instructions can happen in any order, so each cell's
+/// updates are summed up. Any run of at least `MIN_VECTOR_WIDTH` adjacent cells is then updated
+/// `width` bytes at a time, falling back to 16 bytes and then to single cells for what's left of
+/// the run. Blocks without a run that long are left as they are.
+fn vectorize(byte_code: VecDeque<ByteCode>, width: usize, stats: &mut PassStats) -> VecDeque<ByteCode> {
+  let mut optimized = VecDeque::with_capacity(byte_code.len());
+  let mut block = Vec::new();
+  let mut cells = BTreeMap::new();
+  for op in byte_code {
+    let (offset, num) = match op {
+      ByteCode::Add(num) => (0, num),
+      ByteCode::Sub(num) => (0, -num),
+      ByteCode::AddAt(offset, num) => (offset, num),
+      ByteCode::SubAt(offset, num) => (offset, -num),
+      _ => {
+        vectorize_block(&mut block, &mut cells, width, &mut optimized, stats);
+        optimized.push_back(op);
+        continue;
+      },
+    };
+    let cell = cells.entry(offset).or_insert(0u8);
+    *cell = cell.wrapping_add(num as u8);
+    block.push(op);
+  }
+  vectorize_block(&mut block, &mut cells, width, &mut optimized, stats);
+  optimized
+}
+
+/// Emit the cell updates of a single block, given the instructions that made them and their sum
+/// per cell.
+fn vectorize_block(block: &mut Vec<ByteCode>, cells: &mut BTreeMap<isize, u8>, width: usize,
+                   optimized: &mut VecDeque<ByteCode>, stats: &mut PassStats) {
+  let cells = mem::take(cells);
+  let mut runs: Vec<(isize, Vec<u8>)> = Vec::new();
+  for (offset, cell) in cells {
+    match runs.last_mut() {
+      Some(&mut (start, ref mut run)) if start + run.len() as isize == offset => run.push(cell),
+      _ => runs.push((offset, vec![cell])),
+    }
+  }
+  if runs.iter().all(|(_, run)| run.len() < MIN_VECTOR_WIDTH) {
+    optimized.extend(block.drain(..));
+    return;
+  }
+  block.clear();
+
+  for (start, run) in runs {
+    let mut lane = 0;
+    for &vector_width in [width, MIN_VECTOR_WIDTH].iter() {
+      while run.len() - lane >= vector_width {
+        let offset = start + lane as isize;
+        optimized.push_back(ByteCode::AddVector(offset, run[lane..lane + vector_width].to_vec()));
+        stats.rewritten += 1;
+        lane += vector_width;
+      }
+    }
+    for (lane, &cell) in run.iter().enumerate().skip(lane) {
+      match (start + lane as isize, cell) {
+        (_, 0) => {},
+        (0, cell) => optimized.push_back(ByteCode::Add(cell as isize)),
+        (offset, cell) => optimized.push_back(ByteCode::AddAt(offset, cell as isize)),
+      }
+    }
+  }
+}
+
+/// The number of instructions in the loop starting at the beginning of `code`, including its
+/// `Jump` and `JumpNotZero`.
+fn loop_length(code: &[ByteCode]) -> usize {
+  let mut depth = 0;
+  for (index, op) in code.iter().enumerate() {
+    match *op {
+      ByteCode::Jump(_) => depth += 1,
+      ByteCode::JumpNotZero(_) => depth -= 1,
+      _ => {},
+    }
+    if depth == 0 {
+      return index + 1;
+    }
+  }
+  code.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use compile::{Compiler, SimpleCompiler};
+  use parse::{RawParser, Span};
+
+  fn optimize_source(source: &str) -> Vec<ByteCode> {
+    let program = RawParser::new(source.as_bytes().to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).optimize().into();
+    optimized.into_iter().collect()
+  }
+
+  fn evaluate_source(source: &str, budget: usize) -> Vec<ByteCode> {
+    let program = RawParser::new(source.as_bytes().to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized = Optimizer::new(byte_program).enable(Pass::PartialEval).eval_budget(budget)
+                                                .optimize();
+    VecDeque::from(optimized).into_iter().collect()
+  }
+
+  fn has_loop(byte_code: &[ByteCode]) -> bool {
+    byte_code.iter().any(|op| matches!(*op, ByteCode::JumpNotZero(_)))
+  }
+
+  #[test]
+  fn coalesce_adds_and_moves() {
+    assert_eq!(optimize_source("+++-->><<<."),
+               vec![ByteCode::Add(1), ByteCode::MoveLeft(1), ByteCode::Write, ByteCode::Exit]);
+  }
+
+  #[test]
+  fn fold_offsets() {
+    assert_eq!(optimize_source(">>+<+++."),
+               vec![ByteCode::AddAt(2, 1), ByteCode::AddAt(1, 3), ByteCode::MoveRight(1),
+                    ByteCode::Write, ByteCode::Exit]);
+    assert_eq!(optimize_source("+<--<<[>]"),
+               vec![ByteCode::Add(1), ByteCode::SubAt(-1, 2), ByteCode::MoveLeft(3),
+                    ByteCode::ScanRight(1), ByteCode::Exit]);
+  }
+
+  #[test]
+  fn clear_loop() {
+    assert_eq!(optimize_source("+[-]"), vec![ByteCode::Add(1), ByteCode::Clear, ByteCode::Exit]);
+    assert_eq!(optimize_source("+[+++]"), vec![ByteCode::Add(1), ByteCode::Clear, ByteCode::Exit]);
+  }
+
+  #[test]
+  fn even_step_is_not_a_clear_loop() {
+    assert!(has_loop(&optimize_source("+[--]")));
+  }
+
+  #[test]
+  fn scan_loops() {
+    assert_eq!(optimize_source(",[>]"),
+               vec![ByteCode::Read, ByteCode::ScanRight(1), ByteCode::Exit]);
+    assert_eq!(optimize_source(",[<<<<]"),
+               vec![ByteCode::Read, ByteCode::ScanLeft(4), ByteCode::Exit]);
+  }
+
+  #[test]
+  fn multiply_loops() {
+    assert_eq!(optimize_source("+[->+>++<<]"),
+               vec![ByteCode::Add(1), ByteCode::MultiplyAdd(1, 1), ByteCode::MultiplyAdd(2, 2),
+                    ByteCode::Clear,
+                    ByteCode::Exit]);
+    assert_eq!(optimize_source(",>,[<+++>-]"),
+               vec![ByteCode::Read, ByteCode::MoveRight(1), ByteCode::Read,
+                    ByteCode::MultiplyAdd(-1, 3), ByteCode::Clear, ByteCode::Exit]);
+  }
+
+  #[test]
+  fn unbalanced_loops_are_not_idioms() {
+    assert!(has_loop(&optimize_source(",[->+>]")));
+    assert!(has_loop(&optimize_source(",[-->+<]")));
+    assert!(has_loop(&optimize_source(",[>+<<]")));
+  }
+
+  #[test]
+  fn recognize_idioms() {
+    use self::Step::*;
+
+    assert_eq!(recognize_idiom(vec![Add(-1)]), Some(Idiom::Clear(-1)));
+    assert_eq!(recognize_idiom(vec![Add(3)]), Some(Idiom::Clear(3)));
+    assert_eq!(recognize_idiom(vec![Add(-2)]), None);
+    assert_eq!(recognize_idiom(vec![Move(1)]), Some(Idiom::Scan(1)));
+    assert_eq!(recognize_idiom(vec![Move(-1), Move(-2)]), Some(Idiom::Scan(-3)));
+    assert_eq!(recognize_idiom(vec![Move(2), Move(-1)]), None);
+    assert_eq!(recognize_idiom(vec![Add(-1), Move(1), Add(2), Move(-1)]),
+               Some(Idiom::Multiply(vec![(1, 2)])));
+    assert_eq!(recognize_idiom(vec![Add(-1), Move(-2), Move(1), Add(1), Move(1)]), None);
+    assert_eq!(recognize_idiom(vec![Add(-1), Other]), None);
+  }
+
+  #[test]
+  fn nested_idioms() {
+    let optimized = optimize_source(",[[-]>]");
+    assert!(has_loop(&optimized));
+    assert_eq!(optimized[2], ByteCode::Clear);
+  }
+
+  #[test]
+  fn remove_comment_loops() {
+    assert_eq!(optimize_source("[This is a comment, with a loop [+.] in it.]>+."),
+               vec![ByteCode::AddAt(1, 1), ByteCode::MoveRight(1), ByteCode::Write,
+                    ByteCode::Exit]);
+  }
+
+  #[test]
+  fn remove_loops_after_loops() {
+    assert_eq!(optimize_source(",[>]<[-][.]."),
+               vec![ByteCode::Read, ByteCode::ScanRight(1), ByteCode::MoveLeft(1),
+                    ByteCode::Clear, ByteCode::Write, ByteCode::Exit]);
+  }
+
+  #[test]
+  fn keep_loops_after_writes() {
+    assert!(has_loop(&optimize_source(">+<[.-]")));
+    assert!(has_loop(&optimize_source(",>[.]")));
+  }
+
+  #[test]
+  fn evaluate_input_free_program() {
+    assert_eq!(evaluate_source("++++++++[>++++++++<-]>+.+.", 1000),
+               vec![ByteCode::WriteBytes(b"AB".to_vec()), ByteCode::Exit]);
+  }
+
+  #[test]
+  fn evaluate_until_input() {
+    assert_eq!(evaluate_source("+++.>++[>+<-],.", 1000),
+               vec![ByteCode::WriteBytes(vec![3]), ByteCode::Add(3), ByteCode::AddAt(2, 2),
+                    ByteCode::MoveRight(1), ByteCode::Read, ByteCode::Write, ByteCode::Exit]);
+  }
+
+  #[test]
+  fn evaluate_stops_before_loops_that_read() {
+    let optimized = evaluate_source("+.[,.]", 1000);
+    assert_eq!(optimized[0], ByteCode::WriteBytes(vec![1]));
+    assert_eq!(optimized[1], ByteCode::Add(1));
+    assert!(has_loop(&optimized));
+  }
+
+  #[test]
+  fn evaluate_within_budget() {
+    let optimized = evaluate_source("+.+[>+]", 10);
+    assert_eq!(optimized[0], ByteCode::WriteBytes(vec![1]));
+    assert_eq!(optimized[1], ByteCode::Add(2));
+    assert!(has_loop(&optimized));
+    assert_eq!(evaluate_source("+[]", 10).len(), 4);
+  }
+
+  #[test]
+  fn level_zero_runs_nothing() {
+    let program = RawParser::new(b"++>>".to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).level(0).optimize().into();
+    assert_eq!(optimized.len(), 5);
+  }
+
+  #[test]
+  fn disable_pass() {
+    let program = RawParser::new(b",[-]".to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).disable(Pass::LoopIdioms)
+                                                                    .optimize().into();
+    assert_eq!(optimized.len(), 5);
+  }
+
+  #[test]
+  fn report_pass_stats() {
+    let program = RawParser::new(b",>++[-]<[>]+++[->+<]>[<+>.]".to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let (optimized, report) = Optimizer::new(byte_program).optimize_with_report();
+
+    assert_eq!(report.before, 28);
+    assert_eq!(report.after, VecDeque::from(optimized).len());
+    assert_eq!(report.passes.len(), Pass::preset(DEFAULT_OPT_LEVEL).len());
+
+    let coalesce = &report.passes[0];
+    assert_eq!(coalesce.pass, Pass::Coalesce);
+    assert_eq!((coalesce.before, coalesce.after, coalesce.rewritten), (28, 25, 2));
+
+    let idioms = &report.passes[1];
+    assert_eq!(idioms.pass, Pass::LoopIdioms);
+    assert_eq!((idioms.clear, idioms.scan, idioms.multiply, idioms.general), (1, 1, 1, 1));
+    assert_eq!(idioms.rewritten, 4);
+    assert_eq!(idioms.removed(), 8);
+  }
+
+  #[test]
+  fn unroll_hot_loops() {
+    let program = RawParser::new(b",[>.<-]".to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let mut profile = Profile::new();
+    profile.record_loop(0, 5000);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).profile(profile).optimize().into();
+
+    let exits = optimized.iter().filter(|op| matches!(**op, ByteCode::ExitIfZero(_))).count();
+    let writes = optimized.iter().filter(|op| matches!(**op, ByteCode::Write)).count();
+    assert_eq!(exits, UNROLL_FACTOR - 1);
+    assert_eq!(writes, UNROLL_FACTOR);
+  }
+
+  #[test]
+  fn leave_cold_loops_alone() {
+    let program = RawParser::new(b",[>.<-]".to_vec()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let mut profile = Profile::new();
+    profile.record_loop(0, 10);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).profile(profile).optimize().into();
+
+    assert!(!optimized.iter().any(|op| matches!(*op, ByteCode::ExitIfZero(_))));
+  }
+
+  #[test]
+  fn find_cold_loops() {
+    let program = RawParser::new(b",[.[.]],[.]".to_vec()).parse().unwrap();
+    let byte_code: VecDeque<ByteCode> = SimpleCompiler::new().compile_program(&program).into();
+    let mut profile = Profile::new();
+    profile.record_loop(0, 3);
+    profile.record_loop(1, 0);
+
+    assert_eq!(super::find_cold_loops(&byte_code, Some(&profile)), vec![Label::new(2)]);
+    assert!(super::find_cold_loops(&byte_code, None).is_empty());
+  }
+
+  #[test]
+  fn vectorize_adjacent_cells() {
+    let source = format!(",{}.", "+>".repeat(20));
+    let mut expected = vec![ByteCode::Read, ByteCode::AddVector(0, vec![1; 16])];
+    expected.extend((16..20).map(|offset| ByteCode::AddAt(offset, 1)));
+    expected.extend(vec![ByteCode::MoveRight(20), ByteCode::Write, ByteCode::Exit]);
+    assert_eq!(optimize_source(&source), expected);
+
+    let program = RawParser::new(format!(",{}.", "+>".repeat(40)).into_bytes()).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).vector_width(32).optimize().into();
+    assert_eq!(optimized[1], ByteCode::AddVector(0, vec![1; 32]));
+    assert_eq!(optimized[2], ByteCode::AddAt(32, 1));
+  }
+
+  #[test]
+  fn short_runs_are_not_vectorized() {
+    let source = format!(",{}.", "+>".repeat(15));
+    assert!(!optimize_source(&source).iter().any(|op| matches!(*op, ByteCode::AddVector(..))));
+  }
+
+  #[test]
+  fn dump_is_a_barrier() {
+    // Nothing is moved across the dump, it has to see the tape the way the program left it
+    let program = RawParser::new(b"+>++#<-[-]>>#".to_vec()).debug_char(true).parse().unwrap();
+    let byte_program = SimpleCompiler::new().compile_program(&program);
+    let optimized: VecDeque<ByteCode> = Optimizer::new(byte_program).level(3).optimize().into();
+    assert_eq!(Vec::from(optimized),
+               vec![ByteCode::Add(1), ByteCode::AddAt(1, 2), ByteCode::MoveRight(1),
+                    ByteCode::Dump(Span::new(1, 5)), ByteCode::SubAt(-1, 1), ByteCode::MoveLeft(1),
+                    ByteCode::Clear, ByteCode::MoveRight(2), ByteCode::Dump(Span::new(1, 13)), ByteCode::Exit]);
+  }
+}
@@ -0,0 +1,126 @@
+// compile/pass.rs
+// Rustration
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The individual transformations the `Optimizer` can run over a program.
+///
+/// Passes always run in the order they're declared in, no matter which order they're enabled in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pass {
+  /// Combine runs of `Add`/`Sub` and `MoveRight`/`MoveLeft` into single instructions
+  Coalesce,
+
+  /// Replace clear, scan and multiply loops with straight line code
+  LoopIdioms,
+
+  /// Remove loops that are entered on a cell known to be zero
+  DeadLoops,
+
+  /// Unroll loops that a profile shows to be hot
+  Unroll,
+
+  /// Address cells by offset instead of moving the data pointer within a basic block
+  FoldOffsets,
+
+  /// Run the program at compile time until it needs input
+  PartialEval,
+
+  /// Add to runs of adjacent cells a vector at a time
+  Vectorize,
+
+  /// Lay out loops that a profile shows never run after the rest of the program
+  ColdLayout,
+}
+
+/// Every pass, in the order they run.
+pub const PASSES: [Pass; 8] = [
+  Pass::Coalesce,
+  Pass::LoopIdioms,
+  Pass::DeadLoops,
+  Pass::Unroll,
+  Pass::FoldOffsets,
+  Pass::PartialEval,
+  Pass::Vectorize,
+  Pass::ColdLayout,
+];
+
+impl Pass {
+  /// The name of the pass, as accepted on the command line.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Pass::Coalesce => "coalesce",
+      Pass::LoopIdioms => "loop-idioms",
+      Pass::DeadLoops => "dead-loops",
+      Pass::Unroll => "unroll",
+      Pass::FoldOffsets => "fold-offsets",
+      Pass::PartialEval => "partial-eval",
+      Pass::Vectorize => "vectorize",
+      Pass::ColdLayout => "cold-layout",
+    }
+  }
+
+  /// The passes that run at the given optimization level.
+  ///
+  /// `-O0` runs nothing, `-O1` only coalesces instructions, `-O2` runs every pass that keeps the
+  /// program's behaviour at run time and `-O3` also runs the program at compile time. Any higher
+  /// level is the same as `-O3`. The profile guided passes do nothing unless a profile is given.
+  pub fn preset(level: u8) -> Vec<Pass> {
+    match level {
+      0 => vec![],
+      1 => vec![Pass::Coalesce],
+      2 => vec![Pass::Coalesce, Pass::LoopIdioms, Pass::DeadLoops, Pass::Unroll, Pass::FoldOffsets,
+                Pass::Vectorize, Pass::ColdLayout],
+      _ => PASSES.to_vec(),
+    }
+  }
+}
+
+impl fmt::Display for Pass {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+impl FromStr for Pass {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "coalesce" => Ok(Pass::Coalesce),
+      "loop-idioms" => Ok(Pass::LoopIdioms),
+      "dead-loops" => Ok(Pass::DeadLoops),
+      "unroll" => Ok(Pass::Unroll),
+      "fold-offsets" => Ok(Pass::FoldOffsets),
+      "partial-eval" => Ok(Pass::PartialEval),
+      "vectorize" => Ok(Pass::Vectorize),
+      "cold-layout" => Ok(Pass::ColdLayout),
+      _ => Err(format!("Unknown optimization pass: {}", s)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pass_names_round_trip() {
+    for pass in PASSES.iter() {
+      assert_eq!(pass.name().parse::<Pass>(), Ok(*pass));
+    }
+    assert!("inline".parse::<Pass>().is_err());
+  }
+
+  #[test]
+  fn presets_grow_with_level() {
+    for level in 0..3 {
+      let lower = Pass::preset(level);
+      let higher = Pass::preset(level + 1);
+      assert!(lower.iter().all(|pass| higher.contains(pass)));
+      assert!(higher.len() > lower.len());
+    }
+    assert_eq!(Pass::preset(4), Pass::preset(3));
+  }
+}
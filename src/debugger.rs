@@ -0,0 +1,430 @@
+// debugger.rs
+// Rustration
+
+use std::ascii;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use interpreter::{Config, Context, Flush, Instruction, Stepper};
+use parse::ast::Program;
+use parse::Span;
+
+/// The number of cells either side of the data pointer `tape` shows unless told otherwise.
+const DEFAULT_TAPE_WINDOW: usize = 8;
+
+const HELP: &str = "\
+step, s [N]              Run the next N instructions, or just the next one
+next, n                  Run the next instruction, or the whole loop if it's a '['
+continue, c              Run until a breakpoint or the end of the program
+finish, f                Run until the innermost loop exits
+break, b LINE:COLUMN     Stop before the instruction at LINE:COLUMN
+delete, d LINE:COLUMN    Remove the breakpoint at LINE:COLUMN
+tape, t [dec|hex|ascii] [N]
+                         Show N cells either side of the data pointer
+set [CELL] VALUE         Change a cell, or the one under the data pointer
+pointer, p CELL          Move the data pointer to a cell
+where, w                 Show the next instruction to run
+help, h                  Show this list
+quit, q                  Stop debugging";
+
+/// How the values of cells are shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Radix {
+  Dec,
+  Hex,
+  Ascii,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+  Step(usize),
+  Next,
+  Continue,
+  Finish,
+  Break(Span),
+  Delete(Span),
+  Tape(Radix, usize),
+  Set(Option<isize>, u64),
+  Pointer(isize),
+  Where,
+  Help,
+  Quit,
+}
+
+fn parse_span(s: &str) -> Result<Span, String> {
+  let mut parts = s.splitn(2, ':');
+  match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+    (Some(Ok(line)), Some(Ok(column))) => Ok(Span::new(line, column)),
+    _ => Err(format!("Expected a position like 3:14, not {}", s)),
+  }
+}
+
+/// Parse a cell value, in decimal, in hex starting with 0x, or as a character in single quotes.
+fn parse_value(s: &str) -> Result<u64, String> {
+  let bytes = s.as_bytes();
+  let value = if let Some(hex) = s.strip_prefix("0x") {
+    u64::from_str_radix(hex, 16).ok()
+  }
+  else if bytes.len() == 3 && bytes[0] == b'\'' && bytes[2] == b'\'' {
+    Some(u64::from(bytes[1]))
+  }
+  else {
+    s.parse().ok()
+  };
+  value.ok_or_else(|| format!("Expected a value like 65, 0x41 or 'A', not {}", s))
+}
+
+fn parse_cell(s: &str) -> Result<isize, String> {
+  s.parse().map_err(|_| format!("Expected a cell number, not {}", s))
+}
+
+impl FromStr for Command {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    if words.is_empty() {
+      return Err(String::from("Expected a command, type help for a list of commands"));
+    }
+    let command = match (words[0], &words[1..]) {
+      ("step", &[]) | ("s", &[]) => Command::Step(1),
+      ("step", &[count]) | ("s", &[count]) => match count.parse() {
+        Ok(count) if count > 0 => Command::Step(count),
+        _ => return Err(format!("Expected a number of instructions to run, not {}", count)),
+      },
+      ("next", &[]) | ("n", &[]) => Command::Next,
+      ("continue", &[]) | ("c", &[]) => Command::Continue,
+      ("finish", &[]) | ("f", &[]) => Command::Finish,
+      ("break", &[span]) | ("b", &[span]) => Command::Break(parse_span(span)?),
+      ("delete", &[span]) | ("d", &[span]) => Command::Delete(parse_span(span)?),
+      ("tape", args) | ("t", args) if args.len() <= 2 => {
+        let mut radix = Radix::Dec;
+        let mut window = DEFAULT_TAPE_WINDOW;
+        for &arg in args {
+          match arg {
+            "dec" => radix = Radix::Dec,
+            "hex" => radix = Radix::Hex,
+            "ascii" => radix = Radix::Ascii,
+            _ => window = arg.parse().map_err(|_| format!("Expected dec, hex, ascii or a number of cells, not {}", arg))?,
+          }
+        }
+        Command::Tape(radix, window)
+      },
+      ("set", &[value]) => Command::Set(None, parse_value(value)?),
+      ("set", &[cell, value]) => Command::Set(Some(parse_cell(cell)?), parse_value(value)?),
+      ("pointer", &[cell]) | ("p", &[cell]) => Command::Pointer(parse_cell(cell)?),
+      ("where", &[]) | ("w", &[]) => Command::Where,
+      ("help", &[]) | ("h", &[]) => Command::Help,
+      ("quit", &[]) | ("q", &[]) => Command::Quit,
+      _ => return Err(format!("Unknown command: {}, type help for a list of commands", s.trim())),
+    };
+    Ok(command)
+  }
+}
+
+/// Reads no more than a byte at a time, for a program that shares its input with the debugger's
+/// commands.
+///
+/// Stdin is buffered for everything that reads from it, so as long as the program only takes the
+/// bytes it asks for the debugger's commands stay in the buffer for the debugger to read.
+pub struct ByteAtATime<R>(pub R);
+
+impl<R: Read> Read for ByteAtATime<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let len = buf.len().min(1);
+    self.0.read(&mut buf[..len])
+  }
+}
+
+/// A debugger that runs a program one instruction at a time with a `Stepper`, under the control
+/// of commands read one line at a time.
+///
+/// The program stops at breakpoints set by position or by a '#' in the source, and the tape can be
+/// looked at and changed between instructions.
+pub struct Debugger<'a> {
+  stepper: Stepper<'a>,
+  source: Vec<u8>,
+  // The indices of the instructions to stop before
+  breakpoints: BTreeSet<usize>,
+  // Set once the program has stopped with an error, nothing more can run after that
+  stopped: bool,
+}
+
+impl<'a> Debugger<'a> {
+  /// Create a debugger for `program`, parsed from `source`, that runs the program the way `config`
+  /// describes with input from `reader` and output to `writer`.
+  ///
+  /// Output is always flushed straight away so it shows up between commands, and there's a
+  /// breakpoint before the next instruction after each '#' in the source.
+  pub fn new<R, W>(program: &Program, source: &[u8], config: Config, reader: R, writer: W) -> Self
+    where R: Read + 'a, W: Write + 'a
+  {
+    let config = Config { flush: Flush::Always, ..config };
+    let context = Context::with_streams(config, reader, writer);
+    let mut debugger = Debugger {
+      stepper: Stepper::new(program.instructions(), context),
+      source: source.to_vec(),
+      breakpoints: BTreeSet::new(),
+      stopped: false,
+    };
+    for (line_num, line) in source.split(|&byte| byte == b'\n').enumerate() {
+      for (column, _) in line.iter().enumerate().filter(|&(_, &byte)| byte == b'#') {
+        if let Some(index) = debugger.instruction_at(Span::new(line_num + 1, column + 1)) {
+          debugger.breakpoints.insert(index);
+        }
+      }
+    }
+    debugger
+  }
+
+  /// Read commands with `next_command` and write what they show to `out`, until told to quit or
+  /// `next_command` runs out of commands.
+  pub fn run<C, W>(&mut self, mut next_command: C, out: &mut W) -> io::Result<()>
+    where C: FnMut() -> io::Result<Option<String>>, W: Write
+  {
+    self.show_position(out)?;
+    loop {
+      write!(out, "(bf) ")?;
+      out.flush()?;
+      let line = match next_command()? {
+        Some(line) => line,
+        None => return Ok(()),
+      };
+      if line.trim().is_empty() {
+        continue;
+      }
+      match line.parse() {
+        Ok(Command::Quit) => return Ok(()),
+        Ok(command) => self.run_command(command, out)?,
+        Err(err) => writeln!(out, "{}", err)?,
+      }
+    }
+  }
+
+  fn run_command<W: Write>(&mut self, command: Command, out: &mut W) -> io::Result<()> {
+    match command {
+      Command::Step(count) => {
+        let mut remaining = count;
+        self.run_until(out, |_| {
+          remaining -= 1;
+          remaining == 0
+        })
+      },
+      Command::Next => match self.stepper.instructions().get(self.stepper.next_index()) {
+        Some(&(Instruction::LoopStart(end), _)) => self.run_until(out, |stepper| stepper.next_index() == end + 1),
+        _ => self.run_until(out, |_| true),
+      },
+      Command::Continue => self.run_until(out, |_| false),
+      Command::Finish => match self.stepper.enclosing_loop().map(|start| self.stepper.instructions()[start].0) {
+        Some(Instruction::LoopStart(end)) => self.run_until(out, |stepper| stepper.next_index() == end + 1),
+        _ => writeln!(out, "Not inside a loop"),
+      },
+      Command::Break(span) => match self.instruction_at(span) {
+        Some(index) => {
+          self.breakpoints.insert(index);
+          let span = self.stepper.instructions()[index].1;
+          writeln!(out, "Breakpoint set at line: {}, column: {}", span.line, span.column)
+        },
+        None => writeln!(out, "No instructions at or after line: {}, column: {}", span.line, span.column),
+      },
+      Command::Delete(span) => match self.instruction_at(span) {
+        Some(index) if self.breakpoints.remove(&index) => {
+          let span = self.stepper.instructions()[index].1;
+          writeln!(out, "Breakpoint removed from line: {}, column: {}", span.line, span.column)
+        },
+        _ => writeln!(out, "No breakpoint at line: {}, column: {}", span.line, span.column),
+      },
+      Command::Tape(radix, window) => self.show_tape(out, radix, window),
+      Command::Set(cell, value) => {
+        let context = self.stepper.context_mut();
+        let cell = cell.unwrap_or_else(|| context.cell_number());
+        if context.set_cell(cell, value) {
+          writeln!(out, "Cell {} is now {}", cell, context.cell(cell).unwrap())
+        }
+        else {
+          writeln!(out, "The tape hasn't reached cell {}", cell)
+        }
+      },
+      Command::Pointer(cell) => {
+        if self.stepper.context_mut().move_to(cell) {
+          writeln!(out, "The data pointer is now at cell {}", cell)
+        }
+        else {
+          writeln!(out, "The tape hasn't reached cell {}", cell)
+        }
+      },
+      Command::Where => self.show_position(out),
+      Command::Help => writeln!(out, "{}", HELP),
+      Command::Quit => Ok(()),
+    }
+  }
+
+  /// The index of the first instruction at or after `span`.
+  fn instruction_at(&self, span: Span) -> Option<usize> {
+    self.stepper.instructions().iter()
+      .position(|&(_, other)| (other.line, other.column) >= (span.line, span.column))
+  }
+
+  /// Run instructions until `done` returns true after one of them, the next instruction has a
+  /// breakpoint, or the program stops.
+  fn run_until<W, F>(&mut self, out: &mut W, mut done: F) -> io::Result<()>
+    where W: Write, F: FnMut(&Stepper) -> bool
+  {
+    if self.stopped || self.stepper.is_finished() {
+      return writeln!(out, "The program isn't running");
+    }
+    loop {
+      if let Err(err) = self.stepper.step() {
+        self.stopped = true;
+        return writeln!(out, "The program stopped: {}", err);
+      }
+      if self.stepper.is_finished() {
+        return writeln!(out, "The program finished");
+      }
+      if done(&self.stepper) {
+        return self.show_position(out);
+      }
+      if self.breakpoints.contains(&self.stepper.next_index()) {
+        write!(out, "Breakpoint, ")?;
+        return self.show_position(out);
+      }
+    }
+  }
+
+  /// Show the next instruction to run, along with its line of the source.
+  fn show_position<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    let span = match self.stepper.instructions().get(self.stepper.next_index()) {
+      Some(&(_, span)) => span,
+      None => return writeln!(out, "The program isn't running"),
+    };
+    writeln!(out, "stopped at line: {}, column: {}", span.line, span.column)?;
+    if let Some(line) = self.source.split(|&byte| byte == b'\n').nth(span.line - 1) {
+      writeln!(out, "{}", String::from_utf8_lossy(line))?;
+      writeln!(out, "{:>1$}", "^", span.column)?;
+    }
+    Ok(())
+  }
+
+  /// Show the cells up to `window` either side of the data pointer.
+  fn show_tape<W: Write>(&self, out: &mut W, radix: Radix, window: usize) -> io::Result<()> {
+    let context = self.stepper.context();
+    let pointer = context.cell_number();
+    let (leftmost, rightmost) = context.cell_range();
+    let first = (pointer - window as isize).max(leftmost);
+    let last = (pointer + window as isize).min(rightmost);
+    let cells: Vec<String> = (first..last + 1).map(|cell| {
+      let value = context.cell(cell).unwrap();
+      let value = match radix {
+        Radix::Dec => value.to_string(),
+        Radix::Hex => format!("0x{:02x}", value),
+        Radix::Ascii if value <= 0xff => {
+          let escaped: Vec<u8> = ascii::escape_default(value as u8).collect();
+          format!("'{}'", String::from_utf8_lossy(&escaped))
+        },
+        Radix::Ascii => value.to_string(),
+      };
+      if cell == pointer { format!("[{}]", value) } else { value }
+    }).collect();
+    writeln!(out, "cells {}: {}", first, cells.join(" "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use parse::RawParser;
+
+  fn debug(source: &[u8], commands: &str) -> (String, Vec<u8>) {
+    let program = RawParser::new(source.to_vec()).parse().unwrap();
+    let mut output = Vec::new();
+    let mut shown = Vec::new();
+    {
+      let mut debugger = Debugger::new(&program, source, Config::default(), io::empty(), &mut output);
+      let mut commands = commands.lines().map(String::from);
+      debugger.run(|| Ok(commands.next()), &mut shown).unwrap();
+    }
+    (String::from_utf8(shown).unwrap(), output)
+  }
+
+  #[test]
+  fn parse_commands() {
+    assert_eq!("s 3".parse(), Ok(Command::Step(3)));
+    assert_eq!("break 2:7".parse(), Ok(Command::Break(Span::new(2, 7))));
+    assert_eq!("tape 4 hex".parse(), Ok(Command::Tape(Radix::Hex, 4)));
+    assert_eq!("set 'A'".parse(), Ok(Command::Set(None, 65)));
+    assert_eq!("set -1 0x41".parse(), Ok(Command::Set(Some(-1), 65)));
+    assert!("step 0".parse::<Command>().is_err());
+    assert!("break 2".parse::<Command>().is_err());
+    assert!("jump".parse::<Command>().is_err());
+  }
+
+  #[test]
+  fn debug_program() {
+    let source = b"++[->+<]\n#>.";
+    let commands = "break 1:5\ncontinue\ncontinue\ntape\nset 0 1\nfinish\ncontinue\nset 'A'\ntape ascii\nstep 5";
+    let (shown, output) = debug(source, commands);
+    let expected = "stopped at line: 1, column: 1\n\
+                    ++[->+<]\n\
+                    ^\n\
+                    (bf) Breakpoint set at line: 1, column: 5\n\
+                    (bf) Breakpoint, stopped at line: 1, column: 5\n\
+                    ++[->+<]\n\
+                    \x20   ^\n\
+                    (bf) Breakpoint, stopped at line: 1, column: 5\n\
+                    ++[->+<]\n\
+                    \x20   ^\n\
+                    (bf) cells 0: [0] 1\n\
+                    (bf) Cell 0 is now 1\n\
+                    (bf) Breakpoint, stopped at line: 1, column: 5\n\
+                    ++[->+<]\n\
+                    \x20   ^\n\
+                    (bf) Breakpoint, stopped at line: 2, column: 2\n\
+                    #>.\n\
+                    \x20^\n\
+                    (bf) Cell 0 is now 65\n\
+                    (bf) cells 0: ['A'] '\\x03'\n\
+                    (bf) The program finished\n\
+                    (bf) ";
+    assert_eq!(shown, expected);
+    assert_eq!(output, b"\x03");
+  }
+
+  #[test]
+  fn shared_input() {
+    use std::cell::RefCell;
+    use std::io::{BufRead, Cursor};
+
+    // The program and the debugger read from the same stream, like they do when sharing stdin
+    struct Shared<'s>(&'s RefCell<Cursor<&'static [u8]>>);
+
+    impl<'s> Read for Shared<'s> {
+      fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+      }
+    }
+
+    let stream = RefCell::new(Cursor::new(&b"continue\naquit\n"[..]));
+    let program = RawParser::new(b",.".to_vec()).parse().unwrap();
+    let mut output = Vec::new();
+    let mut commands = Vec::new();
+    {
+      let input = ByteAtATime(Shared(&stream));
+      let mut debugger = Debugger::new(&program, b",.", Config::default(), input, &mut output);
+      let next_command = || {
+        let mut line = String::new();
+        stream.borrow_mut().read_line(&mut line)?;
+        commands.push(line.clone());
+        Ok(if line.is_empty() { None } else { Some(line) })
+      };
+      debugger.run(next_command, &mut io::sink()).unwrap();
+    }
+    assert_eq!(output, b"a");
+    assert_eq!(commands, ["continue\n", "quit\n"]);
+  }
+
+  #[test]
+  fn next_steps_over_loops() {
+    let (shown, _) = debug(b"+[-]+", "next\nnext\nwhere\nnext");
+    assert!(shown.ends_with("stopped at line: 1, column: 5\n+[-]+\n    ^\n(bf) The program finished\n(bf) "));
+  }
+}
@@ -0,0 +1,1077 @@
+// interpreter/context.rs
+// Rustration
+
+use std::io::{self, BufWriter, Read, Write};
+use std::fmt;
+use std::str::FromStr;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use compile::Idiom;
+use parse::Span;
+use profile::Profile;
+use super::{Limit, Limits, RunSummary, RuntimeError, Snapshot, Tracer};
+use super::trace::{describe_tape, dump_heading, DUMP_WINDOW};
+
+/// The most bytes of input read in one go.
+const INPUT_CHUNK: usize = 4096;
+
+/// The number of instructions to run between checks of the time limit.
+const TIME_CHECK_INTERVAL: u64 = 4096;
+
+/// The number of bits in a cell unless configured otherwise.
+pub const DEFAULT_CELL_BITS: CellBits = CellBits::Bits8;
+
+/// Options for how a `Context` runs a program.
+#[derive(Debug, Clone)]
+pub struct Config {
+  /// The number of bits in each cell
+  pub cell_bits: CellBits,
+
+  /// What happens when a cell goes past the largest value it can hold or below zero
+  pub overflow: Overflow,
+
+  /// What happens when the data pointer moves off either end of the tape
+  pub tape: TapePolicy,
+
+  /// What ',' does to the cell under the data pointer once there's no input left
+  pub eof: EofPolicy,
+
+  /// When output is flushed, it's always flushed before reading input and once the program stops
+  pub flush: Flush,
+
+  /// Limits on the resources the program can use
+  pub limits: Limits,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      cell_bits: DEFAULT_CELL_BITS,
+      overflow: Overflow::Wrap,
+      tape: TapePolicy::Clamp,
+      eof: EofPolicy::Unchanged,
+      flush: Flush::Line,
+      limits: Limits::default(),
+    }
+  }
+}
+
+/// The number of bits in each cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellBits {
+  Bits8,
+  Bits16,
+  Bits32,
+  Bits64,
+}
+
+impl CellBits {
+  /// The largest value a cell can hold, with every bit of the cell set.
+  pub fn max(self) -> u64 {
+    match self {
+      CellBits::Bits8 => 0xff,
+      CellBits::Bits16 => 0xffff,
+      CellBits::Bits32 => 0xffff_ffff,
+      CellBits::Bits64 => u64::MAX,
+    }
+  }
+}
+
+/// The cell widths, as accepted on the command line.
+pub const CELL_BITS_NAMES: [&str; 4] = ["8", "16", "32", "64"];
+
+impl FromStr for CellBits {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "8" => Ok(CellBits::Bits8),
+      "16" => Ok(CellBits::Bits16),
+      "32" => Ok(CellBits::Bits32),
+      "64" => Ok(CellBits::Bits64),
+      _ => Err(format!("Unsupported cell width: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for CellBits {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      CellBits::Bits8 => write!(f, "8"),
+      CellBits::Bits16 => write!(f, "16"),
+      CellBits::Bits32 => write!(f, "32"),
+      CellBits::Bits64 => write!(f, "64"),
+    }
+  }
+}
+
+/// What happens when a cell goes past the largest value it can hold or below zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+  /// Wrap around to zero or the largest value
+  Wrap,
+
+  /// Stay at the largest value or zero
+  Saturate,
+
+  /// Stop the program with a `RuntimeError`
+  Trap,
+}
+
+/// What happens when the data pointer moves off either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TapePolicy {
+  /// Moving left of the first cell does nothing, the tape grows to the right without limit
+  Clamp,
+
+  /// Moving left of the first cell is an error, the tape grows to the right without limit
+  Error,
+
+  /// The tape grows in both directions without limit
+  Infinite,
+
+  /// A tape of a fixed number of cells, moving off one end comes back around on the other
+  Circular(usize),
+
+  /// A tape of a fixed number of cells, moving off either end is an error
+  Fixed(usize),
+}
+
+impl FromStr for TapePolicy {
+  type Err = String;
+
+  /// Parse a policy from `clamp`, `error`, `infinite`, `circular:<cells>` or `fixed:<cells>`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    let cells = match parts.next().map(str::parse::<usize>) {
+      Some(Ok(0)) => return Err(String::from("A tape needs at least one cell")),
+      Some(Ok(cells)) => Some(cells),
+      Some(Err(err)) => return Err(format!("Invalid number of cells: {}", err)),
+      None => None,
+    };
+    match (name, cells) {
+      ("clamp", None) => Ok(TapePolicy::Clamp),
+      ("error", None) => Ok(TapePolicy::Error),
+      ("infinite", None) => Ok(TapePolicy::Infinite),
+      ("circular", Some(cells)) => Ok(TapePolicy::Circular(cells)),
+      ("fixed", Some(cells)) => Ok(TapePolicy::Fixed(cells)),
+      ("circular", None) | ("fixed", None) => Err(format!("Missing number of cells for tape: {}", s)),
+      _ => Err(format!("Unknown tape policy: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for TapePolicy {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      TapePolicy::Clamp => write!(f, "clamp"),
+      TapePolicy::Error => write!(f, "error"),
+      TapePolicy::Infinite => write!(f, "infinite"),
+      TapePolicy::Circular(cells) => write!(f, "circular:{}", cells),
+      TapePolicy::Fixed(cells) => write!(f, "fixed:{}", cells),
+    }
+  }
+}
+
+/// What ',' does to the cell under the data pointer once there's no input left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EofPolicy {
+  /// Leave the cell as it is, like compiled programs do
+  Unchanged,
+
+  /// Set the cell to zero
+  Zero,
+
+  /// Set the cell to the largest value it can hold, which is -1 for signed cells
+  Max,
+}
+
+/// The names of every EOF policy, as accepted on the command line.
+pub const EOF_NAMES: [&str; 3] = ["unchanged", "zero", "max"];
+
+impl FromStr for EofPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "unchanged" => Ok(EofPolicy::Unchanged),
+      "zero" => Ok(EofPolicy::Zero),
+      "max" => Ok(EofPolicy::Max),
+      _ => Err(format!("Unknown EOF policy: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for EofPolicy {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      EofPolicy::Unchanged => write!(f, "unchanged"),
+      EofPolicy::Zero => write!(f, "zero"),
+      EofPolicy::Max => write!(f, "max"),
+    }
+  }
+}
+
+/// When output is flushed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flush {
+  /// After every byte
+  Always,
+
+  /// After every newline
+  Line,
+
+  /// Only before reading input and once the program stops
+  Exit,
+}
+
+/// The names of every flush policy, as accepted on the command line.
+pub const FLUSH_NAMES: [&str; 3] = ["always", "line", "exit"];
+
+impl FromStr for Flush {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "always" => Ok(Flush::Always),
+      "line" => Ok(Flush::Line),
+      "exit" => Ok(Flush::Exit),
+      _ => Err(format!("Unknown flush policy: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for Flush {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Flush::Always => write!(f, "always"),
+      Flush::Line => write!(f, "line"),
+      Flush::Exit => write!(f, "exit"),
+    }
+  }
+}
+
+/// The names of every overflow behaviour, as accepted on the command line.
+pub const OVERFLOW_NAMES: [&str; 3] = ["wrap", "saturate", "trap"];
+
+impl FromStr for Overflow {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "wrap" => Ok(Overflow::Wrap),
+      "saturate" => Ok(Overflow::Saturate),
+      "trap" => Ok(Overflow::Trap),
+      _ => Err(format!("Unknown overflow behaviour: {}", s)),
+    }
+  }
+}
+
+impl fmt::Display for Overflow {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Overflow::Wrap => write!(f, "wrap"),
+      Overflow::Saturate => write!(f, "saturate"),
+      Overflow::Trap => write!(f, "trap"),
+    }
+  }
+}
+
+/// The context of a virtual machine to run a Brainfuck program on.
+///
+/// Programs read their input from and write their output to the streams the context was created
+/// with, stdin and stdout unless given others.
+pub struct Context<'a> {
+  tape: VecDeque<u64>,
+  // The index in `tape` of the cell the data pointer started on
+  origin: usize,
+  tape_policy: TapePolicy,
+  cell_bits: CellBits,
+  // The largest value a cell can hold, kept from `cell_bits` so it isn't worked out on every write
+  cell_max: u64,
+  overflow: Overflow,
+  current_index: usize,
+  reader: Box<dyn Read + 'a>,
+  writer: BufWriter<Box<dyn Write + 'a>>,
+  flush: Flush,
+  input_buffer: VecDeque<u8>,
+  // Set once reading more input comes back empty
+  input_closed: bool,
+  eof: EofPolicy,
+  profile: Option<Profile>,
+  tracer: Option<Tracer<'a>>,
+  limits: Limits,
+  // The time the time limit runs out, set once the clock is started
+  deadline: Option<Instant>,
+  // The number of instructions run so far
+  steps: u64,
+  // The number of instructions to have run before checking the fuel and time limits again
+  next_check: u64,
+  input_bytes: u64,
+  output_bytes: u64,
+  started: Instant,
+}
+
+impl<'a> Default for Context<'a> {
+  /// Create a new, fresh context with an empty tape and empty input buffer.
+  fn default() -> Self {
+    Context::with_config(Config::default())
+  }
+}
+
+impl<'a> Context<'a> {
+  /// Create a new, fresh context that runs programs the way `config` describes.
+  pub fn with_config(config: Config) -> Self {
+    Context::with_streams(config, io::stdin(), io::stdout())
+  }
+
+  /// Create a new, fresh context that runs programs the way `config` describes, reading input
+  /// from `reader` and writing output to `writer`.
+  pub fn with_streams<R: Read + 'a, W: Write + 'a>(config: Config, reader: R, writer: W) -> Self {
+    let mut context = Context {
+      // Even fixed size tapes grow as they're used, so the tape only ever holds cells that have
+      // been reached and `summary` can tell how far along the tape the program got
+      tape: vec![0].into(),
+      origin: 0,
+      tape_policy: config.tape,
+      cell_bits: config.cell_bits,
+      cell_max: config.cell_bits.max(),
+      overflow: config.overflow,
+      current_index: 0,
+      reader: Box::new(reader),
+      writer: BufWriter::new(Box::new(writer)),
+      flush: config.flush,
+      input_buffer: VecDeque::new(),
+      input_closed: false,
+      eof: config.eof,
+      profile: None,
+      tracer: None,
+      deadline: None,
+      limits: config.limits,
+      steps: 0,
+      next_check: 0,
+      input_bytes: 0,
+      output_bytes: 0,
+      started: Instant::now(),
+    };
+    context.schedule_check();
+    context
+  }
+
+  /// Create a context that carries on from where `snapshot` was taken, with new `limits` and
+  /// streams to read input from and write output to.
+  ///
+  /// The fuel limit counts the instructions run before the snapshot was taken as well.
+  pub fn from_snapshot<R, W>(snapshot: &Snapshot, reader: R, writer: W) -> Self
+    where R: Read + 'a, W: Write + 'a
+  {
+    let mut context = Context::with_streams(snapshot.config.clone(), reader, writer);
+    context.tape = snapshot.cells.iter().cloned().collect();
+    context.origin = snapshot.origin;
+    context.current_index = snapshot.pointer;
+    context.input_buffer = snapshot.input.iter().cloned().collect();
+    context.input_closed = snapshot.input_closed;
+    context.steps = snapshot.steps;
+    context.input_bytes = snapshot.input_bytes;
+    context.output_bytes = snapshot.output_bytes;
+    context.schedule_check();
+    context
+  }
+
+  /// Capture the state of the machine, given that the next instruction to run is at `position`.
+  ///
+  /// Output that's still buffered isn't part of the snapshot, so flush before taking one.
+  pub fn snapshot(&self, position: usize) -> Snapshot {
+    Snapshot {
+      position,
+      config: Config {
+        cell_bits: self.cell_bits,
+        overflow: self.overflow,
+        tape: self.tape_policy,
+        eof: self.eof,
+        flush: self.flush,
+        limits: Limits {
+          // Only what's left of the time limit carries over
+          time: self.limits.time.map(|time| match self.deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => time,
+          }),
+          ..self.limits.clone()
+        },
+      },
+      cells: self.tape.iter().cloned().collect(),
+      origin: self.origin,
+      pointer: self.current_index,
+      input: self.input_buffer.iter().cloned().collect(),
+      input_closed: self.input_closed,
+      steps: self.steps,
+      input_bytes: self.input_bytes,
+      output_bytes: self.output_bytes,
+      ..Snapshot::default()
+    }
+  }
+
+  /// Start timing the program, the time limit counts from here.
+  ///
+  /// Call this just before the program starts running, so setting up the context, or waiting for
+  /// the next line in the REPL, doesn't count against the program's time.
+  pub fn start_clock(&mut self) {
+    self.started = Instant::now();
+    self.deadline = self.limits.time.map(|time| self.started + time);
+    self.schedule_check();
+  }
+
+  /// Count one instruction or loop iteration at `span`, stopping the program if it's out of fuel
+  /// or time, and trace it if the program is being traced.
+  pub fn tick(&mut self, span: Span) -> Result<(), RuntimeError> {
+    self.steps += 1;
+    if self.steps < self.next_check {
+      return Ok(());
+    }
+    if let Some(fuel) = self.limits.fuel {
+      if self.steps > fuel {
+        return Err(RuntimeError::LimitExceeded(span, Limit::Fuel(fuel)));
+      }
+    }
+    if let (Some(deadline), Some(time)) = (self.deadline, self.limits.time) {
+      if Instant::now() >= deadline {
+        return Err(RuntimeError::LimitExceeded(span, Limit::Time(time)));
+      }
+    }
+    if let Some(ref mut tracer) = self.tracer {
+      let traced = tracer.record(span, &self.tape, self.current_index, self.origin);
+      traced.map_err(|err| RuntimeError::Io(Some(span), err))?;
+    }
+    if self.tracer.as_ref().is_some_and(Tracer::is_finished) {
+      self.stop_tracing().map_err(|err| RuntimeError::Io(Some(span), err))?;
+    }
+    self.schedule_check();
+    Ok(())
+  }
+
+  /// Work out when the fuel and time limits next need checking, reading the clock on every
+  /// instruction would be far too slow.
+  fn schedule_check(&mut self) {
+    // Every instruction is traced
+    if self.tracer.is_some() {
+      self.next_check = self.steps + 1;
+      return;
+    }
+    let fuel_check = self.limits.fuel.map_or(u64::MAX, |fuel| fuel.saturating_add(1));
+    let time_check = match self.deadline {
+      Some(_) => self.steps + TIME_CHECK_INTERVAL,
+      None => u64::MAX,
+    };
+    self.next_check = fuel_check.min(time_check);
+  }
+
+  /// Make sure the tape is allowed to grow to `len` cells, `span` is the position of the
+  /// instruction growing it.
+  fn check_growth(&self, span: Span, len: usize) -> Result<(), RuntimeError> {
+    match self.limits.cells {
+      Some(cells) if len > cells => Err(RuntimeError::LimitExceeded(span, Limit::Cells(cells))),
+      _ => Ok(()),
+    }
+  }
+
+  /// Start tracing every instruction with `tracer`.
+  pub fn start_tracing(&mut self, tracer: Tracer<'a>) {
+    self.tracer = Some(tracer);
+    self.schedule_check();
+  }
+
+  /// Stop tracing, writing out whatever's left of the trace.
+  fn stop_tracing(&mut self) -> io::Result<()> {
+    let flushed = self.tracer.take().map_or(Ok(()), |mut tracer| tracer.flush());
+    self.schedule_check();
+    flushed
+  }
+
+  /// Start counting how often each loop runs.
+  pub fn start_profiling(&mut self) {
+    self.profile = Some(Profile::new());
+  }
+
+  /// Record that the loop with the given id ran its body `iterations` times.
+  pub fn record_loop(&mut self, id: usize, iterations: u64) {
+    if let Some(ref mut profile) = self.profile {
+      profile.record_loop(id, iterations);
+    }
+  }
+
+  /// Stop counting and return the counts collected so far.
+  pub fn take_profile(&mut self) -> Option<Profile> {
+    self.profile.take()
+  }
+
+  /// Move the data pointer right one, `span` is the position of the '>' doing it.
+  pub fn move_right(&mut self, span: Span) -> Result<(), RuntimeError> {
+    if self.current_index + 1 < self.tape.len() {
+      self.current_index += 1;
+      return Ok(());
+    }
+    match self.tape_policy {
+      TapePolicy::Circular(cells) if self.tape.len() == cells => self.current_index = 0,
+      TapePolicy::Fixed(cells) if self.tape.len() == cells => return Err(RuntimeError::MovedPastEnd(span, cells)),
+      _ => {
+        self.check_growth(span, self.tape.len() + 1)?;
+        self.tape.push_back(0);
+        self.current_index += 1;
+      },
+    }
+    Ok(())
+  }
+
+  /// Move the data pointer left one, `span` is the position of the '<' doing it.
+  pub fn move_left(&mut self, span: Span) -> Result<(), RuntimeError> {
+    if self.current_index > 0 {
+      self.current_index -= 1;
+      return Ok(());
+    }
+    match self.tape_policy {
+      TapePolicy::Clamp => {},
+      TapePolicy::Error | TapePolicy::Fixed(_) => return Err(RuntimeError::MovedPastStart(span)),
+      TapePolicy::Infinite => {
+        self.check_growth(span, self.tape.len() + 1)?;
+        self.tape.push_front(0);
+        self.origin += 1;
+      },
+      TapePolicy::Circular(cells) => {
+        // Wrapping around reaches the last cell, so the whole tape is in use from here on
+        self.check_growth(span, cells)?;
+        self.tape.resize(cells, 0);
+        self.current_index = cells - 1;
+      },
+    }
+    Ok(())
+  }
+
+  /// The number of the cell under the data pointer, counting from the cell it started on.
+  pub fn cell_number(&self) -> isize {
+    self.current_index as isize - self.origin as isize
+  }
+
+  /// The numbers of the leftmost and rightmost cells the tape has grown to.
+  pub fn cell_range(&self) -> (isize, isize) {
+    (-(self.origin as isize), (self.tape.len() - 1 - self.origin) as isize)
+  }
+
+  // The index in `tape` of the cell with the given number, if the tape has grown to it
+  fn cell_index(&self, number: isize) -> Option<usize> {
+    let index = number + self.origin as isize;
+    if index >= 0 && (index as usize) < self.tape.len() { Some(index as usize) } else { None }
+  }
+
+  /// The value of the cell with the given number, if the tape has grown to it.
+  pub fn cell(&self, number: isize) -> Option<u64> {
+    self.cell_index(number).map(|index| self.tape[index])
+  }
+
+  /// Set the cell with the given number to `value`, cut down to the width of a cell. Return false
+  /// if the tape hasn't grown to that cell.
+  pub fn set_cell(&mut self, number: isize, value: u64) -> bool {
+    match self.cell_index(number) {
+      Some(index) => {
+        self.tape[index] = value & self.cell_max;
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// Move the data pointer straight to the cell with the given number. Return false if the tape
+  /// hasn't grown to that cell.
+  pub fn move_to(&mut self, number: isize) -> bool {
+    match self.cell_index(number) {
+      Some(index) => {
+        self.current_index = index;
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// Retrieve input from the input buffer or the input stream if the input buffer is empty.
+  ///
+  /// Once there's no input left the cell is set according to the EOF policy.
+  pub fn input(&mut self, span: Span) -> Result<(), RuntimeError> {
+    if self.input_buffer.is_empty() && !self.input_closed {
+      // Make sure any prompt has been seen before waiting on input
+      self.flush().and_then(|_| self.read_input(None)).map_err(|err| RuntimeError::Io(Some(span), err))?;
+    }
+    match (self.input_buffer.pop_front(), self.eof) {
+      (Some(input), _) => {
+        self.input_bytes += 1;
+        self.write(input as u64);
+      },
+      (None, EofPolicy::Unchanged) => {},
+      (None, EofPolicy::Zero) => self.write(0),
+      (None, EofPolicy::Max) => self.write(self.cell_max),
+    }
+    Ok(())
+  }
+
+  /// Wait until the ',' at `span` has input to read or the input is closed, without running it.
+  ///
+  /// If the wait is interrupted by a signal once `stop` is set, this gives up with an `Interrupted`
+  /// io error, so a program blocked on input can still be stopped.
+  pub fn wait_for_input(&mut self, span: Span, stop: &AtomicBool) -> Result<(), RuntimeError> {
+    if self.input_buffer.is_empty() && !self.input_closed {
+      self.flush().and_then(|_| self.read_input(Some(stop))).map_err(|err| RuntimeError::Io(Some(span), err))?;
+    }
+    Ok(())
+  }
+
+  /// Read whatever input is available into the input buffer, marking the input as closed once the
+  /// input stream is at EOF. Interrupted reads are retried unless `stop` is set.
+  fn read_input(&mut self, stop: Option<&AtomicBool>) -> io::Result<()> {
+    let mut buffer = [0; INPUT_CHUNK];
+    loop {
+      match self.reader.read(&mut buffer) {
+        Ok(0) => self.input_closed = true,
+        Ok(num_read) => self.input_buffer.extend(buffer[..num_read].iter()),
+        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => match stop {
+          Some(stop) if stop.load(Ordering::Relaxed) => return Err(io::ErrorKind::Interrupted.into()),
+          _ => continue,
+        },
+        Err(err) => return Err(err),
+      }
+      return Ok(());
+    }
+  }
+
+  /// Output the lowest byte of the value stored under the data pointer, `span` is the position of
+  /// the '.' doing it.
+  pub fn output(&mut self, span: Span) -> Result<(), RuntimeError> {
+    if let Some(bytes) = self.limits.output {
+      if self.output_bytes >= bytes {
+        return Err(RuntimeError::LimitExceeded(span, Limit::Output(bytes)));
+      }
+    }
+    self.output_bytes += 1;
+    let byte = self.read() as u8;
+    let result = match self.flush {
+      Flush::Always => self.writer.write_all(&[byte]).and_then(|_| self.writer.flush()),
+      Flush::Line if byte == b'\n' => self.writer.write_all(&[byte]).and_then(|_| self.writer.flush()),
+      _ => self.writer.write_all(&[byte]),
+    };
+    result.map_err(|err| RuntimeError::Io(Some(span), err))
+  }
+
+  /// Describe the data pointer and the `window` cells either side of it.
+  pub fn describe_tape(&self, window: usize) -> String {
+    describe_tape(&self.tape, self.current_index, self.origin, window)
+  }
+
+  /// Write the data pointer and the cells around it to stderr for the '#' at `span`, after any
+  /// output that's been buffered so the two line up.
+  pub fn dump(&mut self, span: Span) -> Result<(), RuntimeError> {
+    let line = format!("{}{}\n", dump_heading(span), self.describe_tape(DUMP_WINDOW));
+    self.flush()
+      .and_then(|_| io::stderr().write_all(line.as_bytes()))
+      .map_err(|err| RuntimeError::Io(Some(span), err))
+  }
+
+  /// Write out any output, and any of the trace, that's been buffered.
+  pub fn flush(&mut self) -> io::Result<()> {
+    if let Some(ref mut tracer) = self.tracer {
+      tracer.flush()?;
+    }
+    self.writer.flush()
+  }
+
+  /// Summarise what the program has done so far.
+  pub fn summary(&self) -> RunSummary {
+    RunSummary {
+      steps: self.steps,
+      cells: self.tape.len(),
+      highest_cell: (self.tape.len() - 1 - self.origin) as isize,
+      pointer: self.cell_number(),
+      input_bytes: self.input_bytes,
+      output_bytes: self.output_bytes,
+      elapsed: self.started.elapsed(),
+    }
+  }
+
+  fn write(&mut self, value: u64) {
+    self.tape[self.current_index] = value;
+  }
+
+  fn read(&self) -> u64 {
+    self.tape[self.current_index]
+  }
+
+  /// Increment the value stored in the cell under the data pointer, `span` is the position of the
+  /// '+' doing it.
+  pub fn increment(&mut self, span: Span) -> Result<(), RuntimeError> {
+    let old = self.tape[self.current_index];
+    if old == self.cell_max {
+      match self.overflow {
+        Overflow::Wrap => self.write(0),
+        Overflow::Saturate => {},
+        Overflow::Trap => return Err(RuntimeError::Overflow(span, self.cell_number())),
+      }
+    }
+    else {
+      self.write(old + 1);
+    }
+    Ok(())
+  }
+
+  /// Decrement the value stored in the cell under the data pointer, `span` is the position of the
+  /// '-' doing it.
+  pub fn decrement(&mut self, span: Span) -> Result<(), RuntimeError> {
+    let old = self.tape[self.current_index];
+    if old == 0 {
+      match self.overflow {
+        Overflow::Wrap => self.write(self.cell_max),
+        Overflow::Saturate => {},
+        Overflow::Trap => return Err(RuntimeError::Underflow(span, self.cell_number())),
+      }
+    }
+    else {
+      self.write(old - 1);
+    }
+    Ok(())
+  }
+
+  /// Return true if a loop matching `idiom` can be run in one go in this context.
+  ///
+  /// Clear and multiply loops rely on cells wrapping around, scan and multiply loops rely on the
+  /// tape growing to the right whenever it's needed.
+  pub fn can_shortcut(&self, idiom: &Idiom) -> bool {
+    // Every iteration of a traced loop shows up in the trace, and running out of fuel has to
+    // stop the program partway through a loop
+    if self.tracer.is_some() || self.limits.fuel.is_some() {
+      return false;
+    }
+    let wraps = self.overflow == Overflow::Wrap;
+    let grows = match self.tape_policy {
+      TapePolicy::Clamp | TapePolicy::Error | TapePolicy::Infinite => self.limits.cells.is_none(),
+      TapePolicy::Circular(_) | TapePolicy::Fixed(_) => false,
+    };
+    match *idiom {
+      Idiom::Clear(_) => wraps,
+      Idiom::Scan(_) => grows,
+      Idiom::Multiply(_) => wraps && grows,
+    }
+  }
+
+  /// Count `steps` instructions and loop iterations that were run in one go by a shortcut.
+  pub fn skip_steps(&mut self, steps: u64) {
+    self.steps += steps;
+  }
+
+  /// Set the value stored in the cell under the data pointer to zero, returning the number of
+  /// times adding `step` to it would have taken to wrap it around to zero.
+  ///
+  /// `step` has to be odd, or the cell might never reach zero.
+  pub fn clear(&mut self, step: isize) -> u64 {
+    // Newton's method doubles the number of correct low bits of the inverse of `step` with every
+    // round, an odd number is its own inverse in the lowest 3 bits
+    let step = step as u64;
+    let inverse = (0..5).fold(step, |inverse, _| inverse.wrapping_mul(2u64.wrapping_sub(step.wrapping_mul(inverse))));
+    let iterations = self.read().wrapping_neg().wrapping_mul(inverse) & self.cell_max;
+    self.write(0);
+    iterations
+  }
+
+  /// Move the data pointer `step` cells at a time until it lands on a zero cell.
+  ///
+  /// A negative `step` scans to the left. If there is no zero cell to the left of the data pointer
+  /// that can be reached in steps of `step` the pointer is left where it is.
+  ///
+  /// Returns the number of steps the pointer took.
+  pub fn scan(&mut self, step: isize) -> u64 {
+    let stride = step.unsigned_abs();
+    if step > 0 {
+      let found = self.tape.range(self.current_index..).step_by(stride).position(|&cell| cell == 0);
+      let steps = match found {
+        Some(steps) => steps,
+        // Every cell past the end of the tape is zero
+        None => (self.tape.len() - self.current_index).div_ceil(stride),
+      };
+      self.current_index += steps * stride;
+      if self.current_index >= self.tape.len() {
+        self.tape.resize(self.current_index + 1, 0);
+      }
+      steps as u64
+    }
+    else {
+      let found = self.tape.range(..=self.current_index).rev().step_by(stride).position(|&cell| cell == 0);
+      let steps = found.unwrap_or(0);
+      self.current_index -= steps * stride;
+      steps as u64
+    }
+  }
+
+  /// Add the value under the data pointer times `factor` to the cell at `offset` from the data
+  /// pointer for each `(offset, factor)` pair, then clear the cell under the data pointer.
+  ///
+  /// Nothing is changed if any of the target cells lie before the start of the tape. Returns the
+  /// number of times the loop would have run, which is the value the cell started with.
+  pub fn multiply(&mut self, targets: &[(isize, isize)]) -> u64 {
+    let value = self.read();
+    if targets.iter().any(|&(offset, _)| offset < -(self.current_index as isize)) {
+      return 0;
+    }
+    for &(offset, factor) in targets {
+      let index = (self.current_index as isize + offset) as usize;
+      if index >= self.tape.len() {
+        self.tape.resize(index + 1, 0);
+      }
+      let product = value.wrapping_mul(factor as u64);
+      self.tape[index] = self.tape[index].wrapping_add(product) & self.cell_max;
+    }
+    self.write(0);
+    value
+  }
+
+  /// Return true if the value stored in the cell under the data pointer is zero, false otherwise.
+  pub fn current_cell_is_zero(&mut self) -> bool {
+    self.tape[self.current_index] == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn context_with_bits(cell_bits: CellBits) -> Context<'static> {
+    Context::with_config(Config { cell_bits, ..Config::default() })
+  }
+
+  fn context_with_overflow(overflow: Overflow) -> Context<'static> {
+    Context::with_config(Config { overflow, ..Config::default() })
+  }
+
+  #[test]
+  fn cells_wrap_at_their_width() {
+    for &(bits, max) in [(CellBits::Bits8, 0xff), (CellBits::Bits16, 0xffff), (CellBits::Bits32, 0xffff_ffff),
+                         (CellBits::Bits64, u64::MAX)].iter() {
+      let mut context = context_with_bits(bits);
+      context.decrement(Span::default()).unwrap();
+      assert_eq!(context.read(), max);
+      assert!(!context.current_cell_is_zero());
+      context.increment(Span::default()).unwrap();
+      assert!(context.current_cell_is_zero());
+    }
+  }
+
+  #[test]
+  fn saturate_at_cell_bounds() {
+    let mut context = context_with_overflow(Overflow::Saturate);
+    context.decrement(Span::default()).unwrap();
+    assert!(context.current_cell_is_zero());
+    context.write(0xff);
+    context.increment(Span::default()).unwrap();
+    assert_eq!(context.read(), 0xff);
+  }
+
+  #[test]
+  fn trap_at_cell_bounds() {
+    let mut context = context_with_overflow(Overflow::Trap);
+    context.move_right(Span::default()).unwrap();
+    assert_eq!(context.decrement(Span::new(3, 7)), Err(RuntimeError::Underflow(Span::new(3, 7), 1)));
+    context.write(0xff);
+    assert_eq!(context.increment(Span::new(4, 1)), Err(RuntimeError::Overflow(Span::new(4, 1), 1)));
+    assert_eq!(context.read(), 0xff);
+  }
+
+  #[test]
+  fn clear_counts_iterations() {
+    for &(bits, step, iterations) in [(CellBits::Bits8, -1, 5), (CellBits::Bits8, 1, 251), (CellBits::Bits8, 3, 169),
+                                      (CellBits::Bits64, 1, u64::MAX - 4)].iter() {
+      let mut context = context_with_bits(bits);
+      context.write(5);
+      assert_eq!(context.clear(step), iterations);
+      assert!(context.current_cell_is_zero());
+    }
+  }
+
+  #[test]
+  fn multiply_wraps_at_cell_width() {
+    let mut context = context_with_bits(CellBits::Bits16);
+    context.write(300);
+    context.multiply(&[(1, 300)]);
+    context.move_right(Span::default()).unwrap();
+    assert_eq!(context.read(), (300 * 300) & 0xffff);
+  }
+
+  fn context_with_tape(tape: TapePolicy) -> Context<'static> {
+    Context::with_config(Config { tape, ..Config::default() })
+  }
+
+  #[test]
+  fn tape_policies() {
+    let span = Span::new(1, 1);
+    let mut context = context_with_tape(TapePolicy::Clamp);
+    assert!(context.move_left(span).is_ok());
+    assert_eq!(context.cell_number(), 0);
+
+    let mut context = context_with_tape(TapePolicy::Error);
+    assert_eq!(context.move_left(span), Err(RuntimeError::MovedPastStart(span)));
+
+    let mut context = context_with_tape(TapePolicy::Infinite);
+    context.move_left(span).unwrap();
+    context.decrement(span).unwrap();
+    assert_eq!(context.cell_number(), -1);
+    context.move_right(span).unwrap();
+    assert!(context.current_cell_is_zero());
+    context.scan(-1);
+    assert_eq!(context.cell_number(), 0);
+
+    let mut context = context_with_tape(TapePolicy::Circular(3));
+    context.increment(span).unwrap();
+    context.move_left(span).unwrap();
+    assert_eq!(context.cell_number(), 2);
+    context.move_right(span).unwrap();
+    assert_eq!(context.read(), 1);
+    context.move_right(span).unwrap();
+    context.move_right(span).unwrap();
+    context.move_right(span).unwrap();
+    assert_eq!(context.cell_number(), 0);
+
+    let mut context = context_with_tape(TapePolicy::Fixed(2));
+    context.move_right(span).unwrap();
+    assert_eq!(context.move_right(span), Err(RuntimeError::MovedPastEnd(span, 2)));
+    assert_eq!(context.cell_number(), 1);
+  }
+
+  #[test]
+  fn edit_cells() {
+    let mut context = context_with_tape(TapePolicy::Infinite);
+    context.move_left(Span::default()).unwrap();
+    context.move_right(Span::default()).unwrap();
+    context.move_right(Span::default()).unwrap();
+    assert_eq!(context.cell_range(), (-1, 1));
+
+    assert!(context.set_cell(-1, 0x1ff));
+    assert_eq!(context.cell(-1), Some(0xff));
+    assert!(!context.set_cell(2, 1));
+    assert_eq!(context.cell(2), None);
+    assert!(context.move_to(-1));
+    assert_eq!(context.read(), 0xff);
+    assert!(!context.move_to(-2));
+    assert_eq!(context.cell_number(), -1);
+    assert_eq!(context.describe_tape(1), "pointer -1 cells -1: [255] 0");
+    assert_eq!(context.describe_tape(0), "pointer -1 cells -1: [255]");
+  }
+
+  #[test]
+  fn eof_policies() {
+    for &(eof, expected) in [(EofPolicy::Unchanged, 7), (EofPolicy::Zero, 0), (EofPolicy::Max, 0xffff)].iter() {
+      let config = Config { eof, cell_bits: CellBits::Bits16, ..Config::default() };
+      let mut context = Context::with_streams(config, &b"a"[..], io::sink());
+      context.input(Span::default()).unwrap();
+      assert_eq!(context.read(), u64::from(b'a'));
+      context.write(7);
+      context.input(Span::default()).unwrap();
+      assert_eq!(context.read(), expected);
+    }
+  }
+
+  #[test]
+  fn raw_bytes() {
+    // Input isn't split into lines or checked for UTF-8, and it's read a chunk at a time
+    let mut input = vec![0xff; INPUT_CHUNK];
+    input.extend_from_slice(b"\0\x80");
+    let mut output = Vec::new();
+    {
+      let config = Config { cell_bits: CellBits::Bits16, ..Config::default() };
+      let mut context = Context::with_streams(config, &input[..], &mut output);
+      context.input(Span::default()).unwrap();
+      assert_eq!(context.read(), 0xff);
+      assert_eq!(context.input_buffer.len(), INPUT_CHUNK - 1);
+      context.input_buffer.clear();
+      context.input(Span::default()).unwrap();
+      assert_eq!(context.read(), 0);
+      context.input(Span::default()).unwrap();
+      assert_eq!(context.read(), 0x80);
+      context.output(Span::default()).unwrap();
+      assert!(!context.input_closed);
+
+      // Only the lowest byte of a wider cell is written
+      context.write(0x1ff);
+      context.output(Span::default()).unwrap();
+    }
+    assert_eq!(output, [0x80, 0xff]);
+  }
+
+  #[test]
+  fn flush_policies() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps hold of everything that's been written to it, so it can be checked while the context
+    // still owns it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+      fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+      }
+
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+
+    for &(flush, flushed) in [(Flush::Always, 2), (Flush::Line, 1), (Flush::Exit, 0)].iter() {
+      let output = Shared::default();
+      let config = Config { flush, ..Config::default() };
+      let mut context = Context::with_streams(config, io::empty(), output.clone());
+      context.write(u64::from(b'\n'));
+      context.output(Span::default()).unwrap();
+      context.write(u64::from(b'a'));
+      context.output(Span::default()).unwrap();
+      assert_eq!(output.0.borrow().len(), flushed);
+      context.flush().unwrap();
+      assert_eq!(*output.0.borrow(), b"\na");
+    }
+  }
+
+  #[test]
+  fn resource_limits() {
+    use std::time::Duration;
+
+    let span = Span::new(2, 5);
+    let limits = |limits: Limits| Context::with_streams(Config { limits, ..Config::default() }, io::empty(), io::sink());
+
+    let mut context = limits(Limits { fuel: Some(3), ..Limits::default() });
+    for _ in 0..3 {
+      context.tick(span).unwrap();
+    }
+    assert_eq!(context.tick(span), Err(RuntimeError::LimitExceeded(span, Limit::Fuel(3))));
+
+    let mut context = limits(Limits { cells: Some(2), ..Limits::default() });
+    context.move_right(span).unwrap();
+    assert_eq!(context.move_right(span), Err(RuntimeError::LimitExceeded(span, Limit::Cells(2))));
+
+    let mut context = limits(Limits { output: Some(1), ..Limits::default() });
+    context.output(span).unwrap();
+    assert_eq!(context.output(span), Err(RuntimeError::LimitExceeded(span, Limit::Output(1))));
+
+    let time = Duration::from_millis(0);
+    let mut context = limits(Limits { time: Some(time), ..Limits::default() });
+    // The clock doesn't run until the program starts
+    assert!((0..2 * TIME_CHECK_INTERVAL).all(|_| context.tick(span).is_ok()));
+    context.start_clock();
+    let stopped = (0..2 * TIME_CHECK_INTERVAL).map(|_| context.tick(span)).find(Result::is_err);
+    assert_eq!(stopped, Some(Err(RuntimeError::LimitExceeded(span, Limit::Time(time)))));
+  }
+
+  #[test]
+  fn parse_tape_policies() {
+    assert_eq!("infinite".parse(), Ok(TapePolicy::Infinite));
+    assert_eq!("circular:30000".parse(), Ok(TapePolicy::Circular(30000)));
+    assert_eq!("fixed:8".parse(), Ok(TapePolicy::Fixed(8)));
+    assert!("fixed".parse::<TapePolicy>().is_err());
+    assert!("circular:0".parse::<TapePolicy>().is_err());
+    assert!("clamp:4".parse::<TapePolicy>().is_err());
+    assert!("mobius".parse::<TapePolicy>().is_err());
+  }
+}
@@ -0,0 +1,101 @@
+// interpreter/error.rs
+// Rustration
+
+use std;
+use std::error::Error;
+use std::io;
+use parse::Span;
+use super::Limit;
+
+/// Errors that stop a program while it's being interpreted.
+#[derive(Debug)]
+pub enum RuntimeError {
+  /// A cell went past the largest value it can hold, at the given position and cell number
+  Overflow(Span, isize),
+
+  /// A cell went below zero, at the given position and cell number
+  Underflow(Span, isize),
+
+  /// The data pointer moved left of the first cell, at the given position
+  MovedPastStart(Span),
+
+  /// The data pointer moved right of the last cell of a tape with the given number of cells, at
+  /// the given position
+  MovedPastEnd(Span, usize),
+
+  /// The program went over one of its resource limits, at the given position
+  LimitExceeded(Span, Limit),
+
+  /// Reading input or writing output failed, at the given position unless it happened while
+  /// flushing output once the program stopped
+  Io(Option<Span>, io::Error),
+}
+
+impl std::fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      RuntimeError::Overflow(span, cell) => {
+        write!(f, "Cell {} overflowed at line: {}, column: {}", cell, span.line, span.column)
+      },
+      RuntimeError::Underflow(span, cell) => {
+        write!(f, "Cell {} underflowed at line: {}, column: {}", cell, span.line, span.column)
+      },
+      RuntimeError::MovedPastStart(span) => {
+        write!(f, "Moved left of the first cell at line: {}, column: {}", span.line, span.column)
+      },
+      RuntimeError::MovedPastEnd(span, cells) => {
+        write!(f, "Moved right of the last of {} cells at line: {}, column: {}", cells, span.line, span.column)
+      },
+      RuntimeError::LimitExceeded(span, limit) => {
+        write!(f, "{} exceeded at line: {}, column: {}", limit, span.line, span.column)
+      },
+      RuntimeError::Io(Some(span), ref err) => {
+        write!(f, "I/O error at line: {}, column: {}: {}", span.line, span.column, err)
+      },
+      RuntimeError::Io(None, ref err) => write!(f, "I/O error: {}", err),
+    }
+  }
+}
+
+impl Error for RuntimeError {
+  fn description(&self) -> &str {
+    match *self {
+      RuntimeError::Overflow(..) => "Cell overflowed",
+      RuntimeError::Underflow(..) => "Cell underflowed",
+      RuntimeError::MovedPastStart(..) => "Moved left of the first cell",
+      RuntimeError::MovedPastEnd(..) => "Moved right of the last cell",
+      RuntimeError::LimitExceeded(..) => "Resource limit exceeded",
+      RuntimeError::Io(..) => "I/O error",
+    }
+  }
+
+  fn cause(&self) -> Option<&dyn Error> {
+    match *self {
+      RuntimeError::Io(_, ref err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+// `io::Error` can't be compared, so I/O errors are equal when they're the same kind of error
+impl PartialEq for RuntimeError {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (&RuntimeError::Overflow(span, cell), &RuntimeError::Overflow(other_span, other_cell)) |
+      (&RuntimeError::Underflow(span, cell), &RuntimeError::Underflow(other_span, other_cell)) => {
+        span == other_span && cell == other_cell
+      },
+      (&RuntimeError::MovedPastStart(span), &RuntimeError::MovedPastStart(other_span)) => span == other_span,
+      (&RuntimeError::MovedPastEnd(span, cells), &RuntimeError::MovedPastEnd(other_span, other_cells)) => {
+        span == other_span && cells == other_cells
+      },
+      (&RuntimeError::LimitExceeded(span, limit), &RuntimeError::LimitExceeded(other_span, other_limit)) => {
+        span == other_span && limit == other_limit
+      },
+      (&RuntimeError::Io(span, ref err), &RuntimeError::Io(other_span, ref other_err)) => {
+        span == other_span && err.kind() == other_err.kind()
+      },
+      _ => false,
+    }
+  }
+}
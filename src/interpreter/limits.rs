@@ -0,0 +1,42 @@
+// interpreter/limits.rs
+// Rustration
+
+use std;
+use std::time::Duration;
+
+/// Limits on the resources a program can use while it's being interpreted, a program that goes
+/// over any of them is stopped.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+  /// The most instructions to run, every loop iteration counts as an instruction as well
+  pub fuel: Option<u64>,
+
+  /// The most cells the tape can grow to
+  pub cells: Option<usize>,
+
+  /// The most bytes to output
+  pub output: Option<u64>,
+
+  /// The longest the program can run for, counted from when it starts running
+  pub time: Option<Duration>,
+}
+
+/// A single limit that a program went over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+  Fuel(u64),
+  Cells(usize),
+  Output(u64),
+  Time(Duration),
+}
+
+impl std::fmt::Display for Limit {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Limit::Fuel(fuel) => write!(f, "Fuel limit of {} instructions", fuel),
+      Limit::Cells(cells) => write!(f, "Tape limit of {} cells", cells),
+      Limit::Output(bytes) => write!(f, "Output limit of {} bytes", bytes),
+      Limit::Time(time) => write!(f, "Time limit of {:.3} seconds", time.as_secs_f64()),
+    }
+  }
+}
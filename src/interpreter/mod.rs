@@ -0,0 +1,178 @@
+//! Brainfuck REPL Interpreter
+//! 
+//! The REPL interpreter allows you to input a Brainfuck program from the command line and see it
+//! execute on the fly.
+//! 
+//! This module also contains the context of the virtual machine used to execute Brainfuck code.
+
+mod context;
+mod error;
+mod limits;
+mod snapshot;
+mod stepper;
+mod summary;
+mod trace;
+
+pub use self::context::{Config, Context, EofPolicy, Flush, Overflow, TapePolicy};
+pub use self::context::{CELL_BITS_NAMES, DEFAULT_CELL_BITS, EOF_NAMES, FLUSH_NAMES, OVERFLOW_NAMES};
+pub use self::error::RuntimeError;
+pub use self::limits::{Limit, Limits};
+pub use self::snapshot::{hash_source, Snapshot};
+pub use self::stepper::{Instruction, Stepper};
+pub use self::summary::RunSummary;
+pub use self::trace::{dump_heading, Tracer, DEFAULT_TRACE_WINDOW, DUMP_WINDOW};
+
+use std;
+use std::str::FromStr;
+use std::io::{self, Write};
+use std::sync::mpsc::{Sender, Receiver};
+use parse::EOF;
+use parse::ReplParser;
+
+enum Command {
+  Quit,
+  Interpret(String),
+}
+
+impl FromStr for Command {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let old = s;
+    match s.trim() {
+      "quit" => Ok(Command::Quit),
+      _ => {
+        Ok(Command::Interpret(old.into()))
+      },
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+  /// The parsing thread is ready for more input
+  Ready,
+
+  /// The parsing thread has exited, this is likely because of a parsing error
+  Exited,
+}
+
+/// A REPL interpreter that takes input from the command line and executes it.
+/// 
+/// Input can be any valid ascii characters, the Brainfuck interpreter will ignore any non command
+/// characters and execute any command characters it recieves. There are some keywords that are
+/// used as commands to the REPL interpreter like `quit` which stops the interpreter.
+pub struct Repl {
+  data_channel: Sender<Vec<u8>>,
+  status_channel: Receiver<Status>,
+  running: bool,
+}
+
+impl Repl {
+  /// Create a new REPL interpreter ready to be run, running code the way `config` describes.
+  ///
+  /// Output is always flushed straight away so it shows up before the next prompt.
+  pub fn new(config: Config) -> Self {
+    let config = Config { flush: Flush::Always, ..config };
+    let (data_tx, data_rx) = std::sync::mpsc::channel();
+    let (status_tx, status_rx) = std::sync::mpsc::channel();
+    let mut parser = ReplParser::new(data_rx, status_tx);
+    let _handle = std::thread::Builder::new()
+      .name(String::from("parse"))
+      .spawn(move|| {
+        let mut context = Context::with_config(config);
+        match parser.parse_and_run(&mut context) {
+          Ok(_) => {},
+          Err(err) => eprintln!("{}", err),
+        }
+      });
+
+    Repl {
+      data_channel: data_tx,
+      status_channel: status_rx,
+      running: false,
+    }
+  }
+
+  /// Start running the REPL interpreter, stopping early if reading commands or showing the prompt
+  /// fails.
+  pub fn start(&mut self) -> io::Result<()> {
+    self.running = true;
+    Repl::display_carrot(false)?;
+    self.wait_for_status();
+    while self.running {
+      let input = match self.read_line() {
+        Ok(input) => input,
+        Err(err) => {
+          self.exit();
+          return Err(err);
+        },
+      };
+      match input {
+        Some(input) => {
+            // parse -> Command cannot fail
+            let command = input.parse().unwrap();
+            self.interpret_command(command)?;
+        },
+        None => self.exit(),
+      }
+    }
+    self.wait_for_exit();
+    Ok(())
+  }
+  
+  fn wait_for_status(&mut self) {
+    match self.status_channel.recv() {
+      Ok(status) => if let Status::Exited = status {
+        self.exit();
+      },
+      Err(_) => self.exit(),
+    }
+  }
+
+  fn wait_for_exit(&self) {
+    while let Ok(status) = self.status_channel.recv() {
+      if let Status::Exited = status {
+        break;
+      }
+    }
+  }
+
+  fn read_line(&mut self) -> io::Result<Option<String>> {
+    let mut buffer = String::new();
+    let num_read = io::stdin().read_line(&mut buffer)?;
+    Ok(if num_read == 0 { None } else { Some(buffer) })
+  }
+
+  fn exit(&mut self) {
+    self.send(vec![EOF]);
+    self.running = false;
+  }
+
+  fn display_carrot(newline: bool) -> io::Result<()> {
+    if newline { println!() };
+    print!("bf> ");
+    io::stdout().flush()
+  }
+
+  fn send(&mut self, data: Vec<u8>) {
+    if self.data_channel.send(data).is_err() {
+      self.running = false;
+      return;
+    }
+    // Wait for the parse thread to parse and execute
+    self.wait_for_status();
+  }
+
+  fn interpret_command(&mut self, command: Command) -> io::Result<()> {
+    match command {
+      Command::Quit => self.exit(),
+      Command::Interpret(input) => {
+        let will_output = input.contains(".");
+        self.send(input.into_bytes());
+        Repl::display_carrot(will_output)?;
+      },
+    }
+    Ok(())
+  }
+}
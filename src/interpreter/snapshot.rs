@@ -0,0 +1,240 @@
+// interpreter/snapshot.rs
+// Rustration
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use super::Config;
+
+/// Everything needed to carry on running a program from part way through, after the interpreter
+/// that was running it has stopped.
+///
+/// Snapshots are stored as plain text, one `<name> <value>` record per line. The cells of the tape
+/// and any input that was read but not used yet are space separated numbers, and the last line is
+/// `end` so a snapshot that was cut short isn't mistaken for a whole one. Limits only have a record
+/// if they're set, and the time limit is kept as the nanoseconds that were left of it.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+  /// The path of the program's source
+  pub program: String,
+
+  /// A hash of the program's source, from `hash_source`
+  pub program_hash: u64,
+
+  /// Whether '#' was an instruction rather than a comment when the program was parsed
+  pub debug_char: bool,
+
+  /// The index of the next instruction to run in the program's flattened instructions
+  pub position: usize,
+
+  /// How the program was being run, with the time limit cut down to what was left of it
+  pub config: Config,
+
+  /// Every cell the tape has grown to
+  pub cells: Vec<u64>,
+
+  /// The index in `cells` of the cell the data pointer started on
+  pub origin: usize,
+
+  /// The index in `cells` of the cell under the data pointer
+  pub pointer: usize,
+
+  /// Input that was read but hasn't been used by the program yet
+  pub input: Vec<u8>,
+
+  /// Whether the end of the input had been reached
+  pub input_closed: bool,
+
+  /// The number of instructions run so far
+  pub steps: u64,
+
+  /// The number of bytes of input used so far
+  pub input_bytes: u64,
+
+  /// The number of bytes output so far
+  pub output_bytes: u64,
+}
+
+impl Snapshot {
+  /// Return true if `source` is the source of the program the snapshot was taken of.
+  pub fn matches(&self, source: &[u8]) -> bool {
+    hash_source(source) == self.program_hash
+  }
+
+  /// Read a snapshot from a file.
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+    data.parse().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+  }
+
+  /// Write the snapshot to a file, replacing the file in one go so an earlier snapshot at `path`
+  /// is never left half overwritten.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let mut partial = path.as_ref().as_os_str().to_owned();
+    partial.push(".partial");
+    {
+      let mut file = File::create(&partial)?;
+      write!(file, "{}", self)?;
+      file.sync_all()?;
+    }
+    fs::rename(&partial, path)
+  }
+}
+
+/// A hash of a program's source, to tell whether it has changed since a snapshot was taken.
+pub fn hash_source(source: &[u8]) -> u64 {
+  // 64 bit FNV-1a
+  source.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+impl fmt::Display for Snapshot {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "program {}", self.program)?;
+    writeln!(f, "hash {:016x}", self.program_hash)?;
+    writeln!(f, "debug-char {}", self.debug_char)?;
+    writeln!(f, "position {}", self.position)?;
+    writeln!(f, "cell-bits {}", self.config.cell_bits)?;
+    writeln!(f, "overflow {}", self.config.overflow)?;
+    writeln!(f, "tape {}", self.config.tape)?;
+    writeln!(f, "eof {}", self.config.eof)?;
+    writeln!(f, "flush {}", self.config.flush)?;
+    if let Some(fuel) = self.config.limits.fuel {
+      writeln!(f, "max-steps {}", fuel)?;
+    }
+    if let Some(cells) = self.config.limits.cells {
+      writeln!(f, "max-cells {}", cells)?;
+    }
+    if let Some(bytes) = self.config.limits.output {
+      writeln!(f, "max-output {}", bytes)?;
+    }
+    if let Some(time) = self.config.limits.time {
+      writeln!(f, "time-left {}", time.as_nanos())?;
+    }
+    writeln!(f, "origin {}", self.origin)?;
+    writeln!(f, "pointer {}", self.pointer)?;
+    writeln!(f, "input-closed {}", self.input_closed)?;
+    writeln!(f, "steps {}", self.steps)?;
+    writeln!(f, "input-bytes {}", self.input_bytes)?;
+    writeln!(f, "output-bytes {}", self.output_bytes)?;
+    write!(f, "cells")?;
+    for cell in self.cells.iter() {
+      write!(f, " {}", cell)?;
+    }
+    write!(f, "\ninput")?;
+    for byte in self.input.iter() {
+      write!(f, " {}", byte)?;
+    }
+    writeln!(f, "\nend")
+  }
+}
+
+/// Parse the value of a record, giving `error` if it isn't valid.
+fn parse_value<T: FromStr>(value: &str, error: &dyn Fn() -> String) -> Result<T, String> {
+  value.parse().map_err(|_| error())
+}
+
+/// Parse the space separated numbers of a record, giving `error` if any of them aren't valid.
+fn parse_values<T: FromStr>(value: &str, error: &dyn Fn() -> String) -> Result<Vec<T>, String> {
+  value.split_whitespace().map(|value| parse_value(value, error)).collect()
+}
+
+impl FromStr for Snapshot {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut snapshot = Snapshot::default();
+    let mut ended = false;
+    for (line_num, line) in s.lines().enumerate() {
+      let mut fields = line.splitn(2, ' ');
+      let name = fields.next().unwrap_or("");
+      let value = fields.next().unwrap_or("");
+      let error = || format!("Invalid snapshot record at line {}: {}", line_num + 1, line);
+      match name {
+        _ if ended => return Err(error()),
+        "program" => snapshot.program = String::from(value),
+        "hash" => snapshot.program_hash = u64::from_str_radix(value, 16).map_err(|_| error())?,
+        "debug-char" => snapshot.debug_char = parse_value(value, &error)?,
+        "position" => snapshot.position = parse_value(value, &error)?,
+        "cell-bits" => snapshot.config.cell_bits = parse_value(value, &error)?,
+        "overflow" => snapshot.config.overflow = parse_value(value, &error)?,
+        "tape" => snapshot.config.tape = parse_value(value, &error)?,
+        "eof" => snapshot.config.eof = parse_value(value, &error)?,
+        "flush" => snapshot.config.flush = parse_value(value, &error)?,
+        "max-steps" => snapshot.config.limits.fuel = Some(parse_value(value, &error)?),
+        "max-cells" => snapshot.config.limits.cells = Some(parse_value(value, &error)?),
+        "max-output" => snapshot.config.limits.output = Some(parse_value(value, &error)?),
+        "time-left" => snapshot.config.limits.time = Some(Duration::from_nanos(parse_value(value, &error)?)),
+        "origin" => snapshot.origin = parse_value(value, &error)?,
+        "pointer" => snapshot.pointer = parse_value(value, &error)?,
+        "input-closed" => snapshot.input_closed = parse_value(value, &error)?,
+        "steps" => snapshot.steps = parse_value(value, &error)?,
+        "input-bytes" => snapshot.input_bytes = parse_value(value, &error)?,
+        "output-bytes" => snapshot.output_bytes = parse_value(value, &error)?,
+        "cells" => snapshot.cells = parse_values(value, &error)?,
+        "input" => snapshot.input = parse_values(value, &error)?,
+        "end" => ended = true,
+        _ => return Err(error()),
+      }
+    }
+    if !ended {
+      return Err(String::from("The snapshot is incomplete"));
+    }
+    if snapshot.origin >= snapshot.cells.len() || snapshot.pointer >= snapshot.cells.len() {
+      return Err(String::from("The snapshot's data pointer isn't on its tape"));
+    }
+    if snapshot.cells.iter().any(|&cell| cell > snapshot.config.cell_bits.max()) {
+      return Err(format!("The snapshot has a cell too large for {} bit cells", snapshot.config.cell_bits));
+    }
+    Ok(snapshot)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use interpreter::{Flush, Limits, TapePolicy};
+
+  #[test]
+  fn snapshot_round_trip() {
+    let snapshot = Snapshot {
+      program: String::from("test_files/hello world.b"),
+      program_hash: hash_source(b"+[.]"),
+      position: 3,
+      config: Config {
+        tape: TapePolicy::Circular(4),
+        flush: Flush::Exit,
+        limits: Limits { fuel: Some(100), time: Some(Duration::new(2, 5)), ..Limits::default() },
+        ..Config::default()
+      },
+      cells: vec![0, 72, 255],
+      origin: 1,
+      pointer: 2,
+      input: b"ab".to_vec(),
+      steps: 12,
+      ..Snapshot::default()
+    };
+    let text = snapshot.to_string();
+    assert!(text.contains("\nmax-steps 100\ntime-left 2000000005\n"));
+    assert!(!text.contains("max-cells"));
+    assert!(text.ends_with("cells 0 72 255\ninput 97 98\nend\n"));
+    let parsed: Snapshot = text.parse().unwrap();
+    assert_eq!(parsed.to_string(), text);
+    assert!(parsed.matches(b"+[.]"));
+    assert!(!parsed.matches(b"+[.]\n"));
+  }
+
+  #[test]
+  fn invalid_snapshots() {
+    let snapshot = Snapshot { cells: vec![0], ..Snapshot::default() }.to_string();
+    assert!(snapshot.parse::<Snapshot>().is_ok());
+    assert!(snapshot.trim_end_matches("end\n").parse::<Snapshot>().is_err());
+    assert!(snapshot.replace("pointer 0", "pointer 1").parse::<Snapshot>().is_err());
+    assert!(snapshot.replace("cells 0", "cells 256").parse::<Snapshot>().is_err());
+    assert!(snapshot.replace("cell-bits 8", "cell-bits 70").parse::<Snapshot>().is_err());
+    assert!(snapshot.replace("steps 0", "steps -1").parse::<Snapshot>().is_err());
+  }
+}
@@ -0,0 +1,243 @@
+// interpreter/stepper.rs
+// Rustration
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use parse::Span;
+use super::{Context, RuntimeError, Snapshot};
+
+/// A single instruction of a program flattened out so it can be run one instruction at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+  MoveRight,
+  MoveLeft,
+  Increment,
+  Decrement,
+  Output,
+  Input,
+  Debug,
+
+  /// A loop's '[', jumping past the ']' at the given index if the cell under the data pointer is 0
+  LoopStart(usize),
+
+  /// A loop's ']', jumping back past the '[' at the given index if the cell under the data pointer
+  /// isn't 0
+  LoopEnd(usize),
+}
+
+/// Runs a flattened program one instruction at a time, so it can be stopped between any two
+/// instructions and the `Context` looked at or changed.
+///
+/// Every instruction that runs counts towards the context's limits, including each ']'.
+pub struct Stepper<'a> {
+  instructions: Vec<(Instruction, Span)>,
+  // The index of the next instruction to run
+  next: usize,
+  context: Context<'a>,
+}
+
+impl<'a> Stepper<'a> {
+  /// Create a stepper that's about to run the first of `instructions` in `context`.
+  pub fn new(instructions: Vec<(Instruction, Span)>, context: Context<'a>) -> Self {
+    Stepper::resume(instructions, context, 0)
+  }
+
+  /// Create a stepper that's about to run the instruction at index `next` of `instructions` in
+  /// `context`, to carry on from a snapshot.
+  pub fn resume(instructions: Vec<(Instruction, Span)>, mut context: Context<'a>, next: usize) -> Self {
+    context.start_clock();
+    Stepper { instructions, next, context }
+  }
+
+  /// The program being run.
+  pub fn instructions(&self) -> &[(Instruction, Span)] {
+    &self.instructions
+  }
+
+  /// The index of the next instruction to run, once the program has finished this is the number
+  /// of instructions.
+  pub fn next_index(&self) -> usize {
+    self.next
+  }
+
+  /// Return true once every instruction has been run.
+  pub fn is_finished(&self) -> bool {
+    self.next >= self.instructions.len()
+  }
+
+  pub fn context(&self) -> &Context<'a> {
+    &self.context
+  }
+
+  pub fn context_mut(&mut self) -> &mut Context<'a> {
+    &mut self.context
+  }
+
+  /// The index of the '[' of the innermost loop around the next instruction, if there is one.
+  pub fn enclosing_loop(&self) -> Option<usize> {
+    let mut depth = 0;
+    for index in (0..self.next.min(self.instructions.len())).rev() {
+      match self.instructions[index].0 {
+        Instruction::LoopStart(_) if depth == 0 => return Some(index),
+        Instruction::LoopStart(_) => depth -= 1,
+        // The loop that this closes can't be around the next instruction
+        Instruction::LoopEnd(_) => depth += 1,
+        _ => {},
+      }
+    }
+    None
+  }
+
+  /// Capture the state of the program, the snapshot's program fields are left for the caller to
+  /// fill in.
+  ///
+  /// Output that's still buffered isn't part of the snapshot, so flush the context before taking one.
+  pub fn snapshot(&self) -> Snapshot {
+    self.context.snapshot(self.next)
+  }
+
+  /// Run instructions until the program finishes or `stop` is set, returning true if it finished.
+  ///
+  /// A ',' waiting on input is only run once the input arrives, so if `stop` is set while it waits
+  /// the ',' is still the next instruction. This runs without the interpreter's shortcuts for
+  /// idioms like `[-]`, so it's slower than `Program::run`.
+  pub fn run(&mut self, stop: &AtomicBool) -> Result<bool, RuntimeError> {
+    while !self.is_finished() {
+      if stop.load(Ordering::Relaxed) {
+        return Ok(false);
+      }
+      if let Some(&(Instruction::Input, span)) = self.instructions.get(self.next) {
+        match self.context.wait_for_input(span, stop) {
+          Err(RuntimeError::Io(_, ref err)) if err.kind() == io::ErrorKind::Interrupted => return Ok(false),
+          result => result?,
+        }
+      }
+      self.step()?;
+    }
+    Ok(true)
+  }
+
+  /// Run the next instruction, flushing output once the program finishes.
+  pub fn step(&mut self) -> Result<(), RuntimeError> {
+    let (instruction, span) = match self.instructions.get(self.next) {
+      Some(&next) => next,
+      None => return Ok(()),
+    };
+    self.context.tick(span)?;
+    self.next = match instruction {
+      Instruction::LoopStart(end) if self.context.current_cell_is_zero() => end + 1,
+      Instruction::LoopEnd(start) if !self.context.current_cell_is_zero() => start + 1,
+      _ => self.next + 1,
+    };
+    match instruction {
+      Instruction::MoveRight => self.context.move_right(span)?,
+      Instruction::MoveLeft => self.context.move_left(span)?,
+      Instruction::Increment => self.context.increment(span)?,
+      Instruction::Decrement => self.context.decrement(span)?,
+      Instruction::Output => self.context.output(span)?,
+      Instruction::Input => self.context.input(span)?,
+      Instruction::Debug => self.context.dump(span)?,
+      Instruction::LoopStart(_) | Instruction::LoopEnd(_) => {},
+    }
+    if self.is_finished() {
+      self.context.flush().map_err(|err| RuntimeError::Io(None, err))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io;
+  use parse::RawParser;
+  use interpreter::Config;
+
+  #[test]
+  fn step_through_loops() {
+    let program = RawParser::new(b"++[>+[-]<-]>.".to_vec()).parse().unwrap();
+    let mut output = Vec::new();
+    {
+      let context = Context::with_streams(Config::default(), io::empty(), &mut output);
+      let mut stepper = Stepper::new(program.instructions(), context);
+      assert_eq!(stepper.instructions()[2], (Instruction::LoopStart(10), Span::new(1, 3)));
+      assert_eq!(stepper.instructions()[10], (Instruction::LoopEnd(2), Span::new(1, 11)));
+
+      // Into the inner loop
+      for _ in 0..6 {
+        stepper.step().unwrap();
+      }
+      assert_eq!(stepper.next_index(), 6);
+      assert_eq!(stepper.enclosing_loop(), Some(5));
+      // Out of the inner loop and back around the outer one
+      for _ in 0..5 {
+        stepper.step().unwrap();
+      }
+      assert_eq!(stepper.next_index(), 3);
+      assert_eq!(stepper.enclosing_loop(), Some(2));
+      while !stepper.is_finished() {
+        stepper.step().unwrap();
+      }
+      assert_eq!(stepper.enclosing_loop(), None);
+      assert_eq!(stepper.context().cell_number(), 1);
+    }
+    assert_eq!(output, b"\0");
+  }
+
+  #[test]
+  fn resume_from_snapshot() {
+    let program = RawParser::new(b",.++[-],.,.".to_vec()).parse().unwrap();
+    let mut output = Vec::new();
+    let snapshot = {
+      let context = Context::with_streams(Config::default(), &b"abc"[..], &mut output);
+      let mut stepper = Stepper::new(program.instructions(), context);
+      // Part way through the second iteration of the loop
+      for _ in 0..7 {
+        stepper.step().unwrap();
+      }
+      stepper.context_mut().flush().unwrap();
+      stepper.snapshot()
+    };
+    assert_eq!((snapshot.position, snapshot.cells.as_slice()), (5, &[98][..]));
+    assert_eq!(snapshot.input, b"bc");
+
+    // Round trip through the text format on the way
+    let snapshot: Snapshot = snapshot.to_string().parse().unwrap();
+    {
+      let context = Context::from_snapshot(&snapshot, io::empty(), &mut output);
+      let mut stepper = Stepper::resume(program.instructions(), context, snapshot.position);
+      assert!(!stepper.run(&AtomicBool::new(true)).unwrap());
+      assert!(stepper.run(&AtomicBool::new(false)).unwrap());
+      assert_eq!(stepper.context().summary().input_bytes, 3);
+      assert_eq!(stepper.context().summary().steps, 207);
+    }
+    assert_eq!(output, b"abc");
+  }
+
+  /// A reader that's interrupted by a stop signal whenever it's read from.
+  struct Signalled<'a>(&'a AtomicBool);
+
+  impl<'a> io::Read for Signalled<'a> {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+      self.0.store(true, Ordering::Relaxed);
+      Err(io::ErrorKind::Interrupted.into())
+    }
+  }
+
+  #[test]
+  fn stop_while_waiting_on_input() {
+    let program = RawParser::new(b"+.,.".to_vec()).parse().unwrap();
+    let stop = AtomicBool::new(false);
+    let mut output = Vec::new();
+    {
+      let context = Context::with_streams(Config::default(), Signalled(&stop), &mut output);
+      let mut stepper = Stepper::new(program.instructions(), context);
+      assert!(!stepper.run(&stop).unwrap());
+      // The ',' hasn't run, so it's run again on resume
+      assert_eq!(stepper.snapshot().position, 2);
+      assert_eq!(stepper.context().summary().steps, 2);
+    }
+    // Output was flushed before waiting
+    assert_eq!(output, b"\x01");
+  }
+}
@@ -0,0 +1,29 @@
+// interpreter/summary.rs
+// Rustration
+
+use std::time::Duration;
+
+/// What a program did over a run that finished without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RunSummary {
+  /// The number of instructions run, every loop iteration counts as an instruction as well
+  pub steps: u64,
+
+  /// The number of cells the tape grew to
+  pub cells: usize,
+
+  /// The number of the rightmost cell the data pointer reached, counting from the cell it started on
+  pub highest_cell: isize,
+
+  /// The number of the cell under the data pointer once the program stopped
+  pub pointer: isize,
+
+  /// The number of bytes of input read
+  pub input_bytes: u64,
+
+  /// The number of bytes output
+  pub output_bytes: u64,
+
+  /// How long the program ran for
+  pub elapsed: Duration,
+}
@@ -0,0 +1,125 @@
+// interpreter/trace.rs
+// Rustration
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use parse::Span;
+
+/// The number of cells either side of the data pointer a trace shows unless configured otherwise.
+pub const DEFAULT_TRACE_WINDOW: usize = 3;
+
+/// The number of cells either side of the data pointer a '#' dump shows, in the interpreter and
+/// in compiled programs alike.
+pub const DUMP_WINDOW: usize = 8;
+
+/// Writes a line for every instruction a program runs, giving its position in the source, the
+/// cell under the data pointer and the cells around it as the instruction starts.
+///
+/// Every loop iteration is traced as another run of the loop's '['.
+pub struct Tracer<'a> {
+  writer: Box<dyn Write + 'a>,
+  source: Vec<u8>,
+  // The index in `source` of the start of each line
+  line_starts: Vec<usize>,
+  window: usize,
+  limit: Option<u64>,
+  written: u64,
+  // Set once the limit has been reached, nothing more is traced after that
+  finished: bool,
+}
+
+impl<'a> Tracer<'a> {
+  /// Create a tracer for the program in `source` that writes to `writer`, showing `window` cells
+  /// either side of the data pointer and stopping once it's written `limit` bytes.
+  pub fn new<W: Write + 'a>(writer: W, source: &[u8], window: usize, limit: Option<u64>) -> Self {
+    let line_starts = Some(0).into_iter()
+      .chain(source.iter().enumerate().filter(|&(_, &byte)| byte == b'\n').map(|(index, _)| index + 1))
+      .collect();
+    Tracer {
+      writer: Box::new(writer),
+      source: source.to_vec(),
+      line_starts,
+      window,
+      limit,
+      written: 0,
+      finished: false,
+    }
+  }
+
+  /// Return true once the tracer has written as much as it's allowed to.
+  pub fn is_finished(&self) -> bool {
+    self.finished
+  }
+
+  /// The source character at `span`.
+  fn instruction_at(&self, span: Span) -> char {
+    self.line_starts.get(span.line.wrapping_sub(1))
+      .and_then(|&start| self.source.get(start + span.column - 1))
+      .map_or('?', |&byte| byte as char)
+  }
+
+  /// Trace the instruction at `span`, the data pointer is at `index` in `tape` and the cell it
+  /// started on is at `origin`.
+  pub fn record(&mut self, span: Span, tape: &VecDeque<u64>, index: usize, origin: usize) -> io::Result<()> {
+    if self.finished {
+      return Ok(());
+    }
+    let line = format!("{}:{} {} {}\n", span.line, span.column, self.instruction_at(span),
+                       describe_tape(tape, index, origin, self.window));
+    if let Some(limit) = self.limit {
+      if self.written + line.len() as u64 > limit {
+        self.finished = true;
+        writeln!(self.writer, "Trace limit of {} bytes reached", limit)?;
+        return self.writer.flush();
+      }
+    }
+    self.written += line.len() as u64;
+    self.writer.write_all(line.as_bytes())
+  }
+
+  /// Write out any of the trace that's been buffered.
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.writer.flush()
+  }
+}
+
+/// The start of the line a '#' dump at `span` writes, `describe_tape` follows it.
+pub fn dump_heading(span: Span) -> String {
+  format!("# {}:{} ", span.line, span.column)
+}
+
+/// Describe the data pointer at `index` in `tape` and the `window` cells either side of it, with
+/// cells numbered from the one at `origin`.
+pub fn describe_tape(tape: &VecDeque<u64>, index: usize, origin: usize, window: usize) -> String {
+  let first = index.saturating_sub(window);
+  let last = (index + window + 1).min(tape.len());
+  let cells: Vec<String> = (first..last).map(|cell| {
+    if cell == index { format!("[{}]", tape[cell]) } else { tape[cell].to_string() }
+  }).collect();
+  format!("pointer {} cells {}: {}", index as isize - origin as isize,
+          first as isize - origin as isize, cells.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn trace_instructions() {
+    let mut output = Vec::new();
+    {
+      let mut tracer = Tracer::new(&mut output, b"+\n>>[-]", 1, Some(80));
+      let tape: VecDeque<u64> = vec![1, 2, 3, 4].into();
+      tracer.record(Span::new(1, 1), &tape, 0, 0).unwrap();
+      tracer.record(Span::new(2, 3), &tape, 2, 1).unwrap();
+      assert!(!tracer.is_finished());
+      tracer.record(Span::new(2, 4), &tape, 3, 1).unwrap();
+      assert!(tracer.is_finished());
+      tracer.record(Span::new(2, 5), &tape, 3, 1).unwrap();
+    }
+    let expected = "1:1 + pointer 0 cells 0: [1] 2\n\
+                    2:3 [ pointer 1 cells 0: 2 [3] 4\n\
+                    Trace limit of 80 bytes reached\n";
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+  }
+}
@@ -44,35 +44,87 @@
 //! 
 
 extern crate clap;
-use clap::{Arg, App};
+extern crate libc;
+use clap::{Arg, App, AppSettings, ArgGroup, ArgMatches, SubCommand};
 
 use std::fs::File;
 use std::path::Path;
-use std::io::Read;
+use std::io::{BufWriter, Read};
 use std::process::Command;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::error::Error;
 use parse::RawParser;
-use interpreter::Repl;
+use interpreter::{Config, Context, EofPolicy, Flush, Limits, Overflow, Repl, RuntimeError, TapePolicy, Tracer};
+use interpreter::{Snapshot, Stepper};
+use interpreter::{CELL_BITS_NAMES, EOF_NAMES, FLUSH_NAMES, OVERFLOW_NAMES};
 use compile::Compiler;
-use compile::Optimizer;
+use compile::{Optimizer, Pass, PASSES};
+use profile::Profile;
+use debugger::{ByteAtATime, Debugger};
 
 mod interpreter;
 mod compile;
 mod parse;
+mod profile;
+mod debugger;
 
 const GENERAL_ERR: i32 = -1;
 const PARSE_ERR: i32 = -2;
 //const COMPILE_ERR: i32 = -3;
 const ASSEMBLE_ERR: i32 = -4;
 const LINK_ERR: i32 = -5;
+const OVERFLOW_ERR: i32 = -6;
+const TAPE_ERR: i32 = -7;
+const LIMIT_ERR: i32 = -8;
+const IO_ERR: i32 = -9;
+const INTERRUPTED_ERR: i32 = -10;
+
+/// The number of hottest loops the profile subcommand lists unless told otherwise.
+const DEFAULT_TOP_LOOPS: usize = 10;
+
+/// Set once the program has been asked to stop by SIGINT or SIGTERM, so a checkpoint can be
+/// taken before it does.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Where the interpreter's trace goes and how much it shows.
+#[derive(Debug)]
+struct TraceOptions {
+  file: Option<String>,
+  window: usize,
+  limit: Option<u64>,
+}
 
 #[derive(Debug)]
 enum Mode {
   Interpret {
     repl: bool,
+    profile_file: Option<String>,
+    stats: bool,
+    trace: Option<TraceOptions>,
+    checkpoint_file: Option<String>,
+    config: Config,
+  },
+  Resume {
+    snapshot_file: String,
+  },
+  Profile {
+    top: usize,
+    config: Config,
+  },
+  Debug {
+    input_file: Option<String>,
+    config: Config,
   },
   Compile {
-    optimized: bool,
+    opt_level: u8,
+    enable_passes: Vec<Pass>,
+    disable_passes: Vec<Pass>,
+    print_after: Vec<Pass>,
+    eval_budget: usize,
+    vector_width: usize,
+    opt_report: bool,
+    profile_file: Option<String>,
     no_assemble: bool,
     no_link: bool,
     output_file: String,
@@ -82,45 +134,61 @@ enum Mode {
 // For now lets interpret, maybe we can compile in the future...
 fn main() {
   // Usage: rustration (-c [-O] [-o output-file] | -i) (input-file | -)
+  //        rustration profile [--top N] input-file
+  //        rustration debug [--input input-file] input-file
+  //        rustration resume snapshot-file
   // -h, --help Help message
-  // -O, --optimize Optimize the compiled output
+  // -O, --optimize Optimize the compiled output, -O0 through -O3, -O on its own is -O2
+  // --enable-pass Run an optimization pass on top of the optimization level
+  // --disable-pass Don't run an optimization pass
+  // --print-after Print the byte code after an optimization pass
+  // --eval-budget Maximum number of instructions to run at compile time for partial-eval
+  // --vector-width Width in bytes of the vector adds the vectorize pass emits, 16 or 32
+  // --opt-report Print what each optimization pass did
+  // --profile-use Guide optimization with a profile from --profile-generate
+  // --profile-generate Write a profile of the interpreted program
+  // --stats Print statistics about the interpreted program once it stops
+  // --trace Print every instruction the interpreter runs along with the tape around the pointer
+  // --trace-file Write the trace to a file instead of stderr
+  // --trace-window Number of cells either side of the pointer to show in the trace
+  // --trace-limit Stop tracing after writing this many bytes
+  // --checkpoint Write a snapshot to this file when SIGINT or SIGTERM stops the interpreted program,
+  //              which then runs without the loop shortcuts
+  // --debug-char Treat '#' as an instruction that dumps the pointer and the cells around it to
+  //              stderr, when interpreting or compiling
   // -S, --assembly Only run compile steps
   // -c, --no-link Only run compile and assemble steps
   // -o, --out-file Output file
   // -i, --interpret Interpret the file
+  // --cell-bits Number of bits in each cell of the interpreter, 8, 16, 32 or 64
+  // --overflow What happens when a cell overflows in the interpreter, wrap, saturate or trap
+  // --tape What happens when the interpreter moves off the tape, clamp, error, infinite,
+  //        circular:<cells> or fixed:<cells>
+  // --eof What ',' does at the end of input in the interpreter, unchanged, zero or max
+  // --flush When the interpreter flushes output, always, line or exit
+  // --max-steps Stop the interpreter after running this many instructions
+  // --max-cells Stop the interpreter if the tape grows past this many cells
+  // --max-output Stop the interpreter after it outputs this many bytes
+  // --timeout Stop the interpreter after running for this many seconds
+  //
+  // profile Interpret the file, then print its source annotated with how many instructions ran
+  //         on each line and the hottest loops, takes the same interpreter arguments as -i
+  // --top Number of hottest loops to list
+  //
+  // debug Step through the file with an interactive debugger, takes the same interpreter
+  //       arguments as -i, type help once it's running for a list of commands
+  // --input Read the program's input from a file instead of stdin
   //
-  let matches = App::new("Rustration")
-                        .version("0.1")
-                        .author("Daniel Seitz")
-                        .about("A command line interpreter/compiler for Brainfuck")
-                        .arg(Arg::with_name("optimize")
-                             .short("O")
-                             .long("optimize")
-                             .help("Optimize the compiled output, does nothing if you are running with -i"))
-                        .arg(Arg::with_name("assembly")
-                             .short("S")
-                             .long("assembly")
-                             .help("Only run compile steps, does nothing if you are running with -i"))
-                        .arg(Arg::with_name("no-link")
-                             .short("-c")
-                             .long("no-link")
-                             .help("Only run compile and assemble steps, does nothgin if you are running with -i"))
-                        .arg(Arg::with_name("output")
-                             .short("o")
-                             .long("out-file")
-                             .help("Output file name, does nothing if you are running with -i")
-                             .value_name("FILE")
-                             .takes_value(true))
-                        .arg(Arg::with_name("interpret")
-                             .short("i")
-                             .long("interpret")
-                             .help("Interpret and run the input file without compiling"))
-                        .arg(Arg::with_name("INPUT")
-                             .help("The input file to use or - for stdin")
-                             .required(true))
-                        .get_matches();
-
-  let in_file = matches.value_of("INPUT").unwrap();
+  // resume Carry on running a program from a snapshot written by --checkpoint, taking another
+  //        snapshot in the same file if it's stopped again, the limits it was started with carry
+  //        over along with what was left of its time limit
+  //
+  let pass_names: Vec<&str> = PASSES.iter().map(Pass::name).collect();
+  let matches = app(&pass_names).get_matches();
+
+  // Subcommands take their own input file and interpreter arguments
+  let args = matches.subcommand().1.unwrap_or(&matches);
+  let in_file = args.value_of("INPUT").unwrap();
   let mut in_file_stem = String::from(Path::new(in_file).file_stem().unwrap().to_str().unwrap());
   let no_assemble = matches.is_present("assembly");
   let no_link = matches.is_present("no-link");
@@ -135,30 +203,68 @@ fn main() {
   else {
     "a.out"
   };
-  let mode = match (matches.is_present("interpret"), in_file) {
-    (true, "-") => Mode::Interpret { repl: true },
-    (true, _) => Mode::Interpret { repl: false },
-    (false, _) => Mode::Compile { 
-      optimized: matches.is_present("optimize"),
+  let config = config_of(args);
+  let debug_char = args.is_present("debug-char");
+  let mode = match (matches.subcommand_name(), matches.is_present("interpret"), in_file) {
+    (Some("profile"), _, _) => Mode::Profile {
+      top: args.value_of("top").map_or(DEFAULT_TOP_LOOPS, |top| top.parse().unwrap()),
+      config,
+    },
+    (Some("debug"), _, _) => Mode::Debug {
+      input_file: args.value_of("input").map(String::from),
+      config,
+    },
+    (Some("resume"), _, _) => Mode::Resume { snapshot_file: String::from(in_file) },
+    (_, true, "-") => Mode::Interpret { repl: true, profile_file: None, stats: false, trace: None, checkpoint_file: None, config },
+    (_, true, _) => Mode::Interpret {
+      repl: false,
+      profile_file: matches.value_of("profile-generate").map(String::from),
+      stats: matches.is_present("stats"),
+      trace: if matches.is_present("trace") || matches.is_present("trace-file") {
+        Some(TraceOptions {
+          file: matches.value_of("trace-file").map(String::from),
+          window: matches.value_of("trace-window").map_or(interpreter::DEFAULT_TRACE_WINDOW, |cells| cells.parse().unwrap()),
+          limit: matches.value_of("trace-limit").map(|bytes| bytes.parse().unwrap()),
+        })
+      }
+      else {
+        None
+      },
+      checkpoint_file: matches.value_of("checkpoint").map(String::from),
+      config,
+    },
+    (_, false, _) => Mode::Compile { 
+      opt_level: opt_level_of(&matches),
+      enable_passes: passes_of(&matches, "enable-pass"),
+      disable_passes: passes_of(&matches, "disable-pass"),
+      print_after: passes_of(&matches, "print-after"),
+      opt_report: matches.is_present("opt-report"),
+      profile_file: matches.value_of("profile-use").map(String::from),
+      eval_budget: matches.value_of("eval-budget").map_or(compile::DEFAULT_EVAL_BUDGET, |steps| steps.parse().unwrap()),
+      vector_width: matches.value_of("vector-width").map_or(compile::DEFAULT_VECTOR_WIDTH, |width| width.parse().unwrap()),
       no_assemble: matches.is_present("assembly"),
       no_link: matches.is_present("no-link"),
-      output_file: String::from(matches.value_of("output").unwrap_or(&default_out_file)),
+      output_file: String::from(matches.value_of("output").unwrap_or(default_out_file)),
     },
   };
 
   match mode {
-    Mode::Compile { optimized, output_file, no_assemble, no_link } => {
+    Mode::Compile { opt_level, enable_passes, disable_passes, print_after, eval_budget, vector_width, opt_report, profile_file, output_file, no_assemble, no_link } => {
       let data = match read_file(in_file) {
         Ok(data) => data,
         Err(err) => {
           exit_with_error(GENERAL_ERR, err);
         },
       };
-      println!("Compiling with optimization: {}, to output file: {}, from input file: {}", optimized, output_file, in_file);
+      println!("Compiling with optimization: -O{}, to output file: {}, from input file: {}", opt_level, output_file, in_file);
       // Stage 1: Lex + Parse
-      let mut parser = RawParser::new(data);
+      let mut parser = RawParser::new(data).debug_char(debug_char);
       match parser.parse() {
         Ok(program) => {
+          for warning in program.warnings() {
+            println!("Warning: {}", warning);
+          }
+
           // Stage 2: Compile to bytecode
           let mut compiler = compile::SimpleCompiler::new();
           let byte_program = compiler.compile_program(&program);
@@ -175,14 +281,33 @@ fn main() {
             Err(err) => exit_with_error(GENERAL_ERR, err),
           };
 
-          if !optimized {
-            byte_program.emit(&mut asm_out);
+          let mut optimizer = Optimizer::new(byte_program).level(opt_level)
+                                                          .eval_budget(eval_budget)
+                                                          .vector_width(vector_width);
+          for pass in enable_passes {
+            optimizer = optimizer.enable(pass);
           }
-          else {
-            let optimizer = Optimizer::new(byte_program);
-            let optimized = optimizer.optimize();
-            optimized.emit(&mut asm_out);
+          for pass in disable_passes {
+            optimizer = optimizer.disable(pass);
           }
+          for pass in print_after {
+            optimizer = optimizer.print_after(pass);
+          }
+          if let Some(profile_file) = profile_file {
+            match Profile::load(profile_file) {
+              Ok(profile) => optimizer = optimizer.profile(profile),
+              Err(err) => exit_with_error(GENERAL_ERR, err),
+            }
+          }
+          let optimized = if opt_report {
+            let (optimized, report) = optimizer.optimize_with_report();
+            println!("{}", report);
+            optimized
+          }
+          else {
+            optimizer.optimize()
+          };
+          optimized.emit(&mut asm_out);
 
           // Stage 4: Assemble
           if no_assemble {
@@ -213,28 +338,467 @@ fn main() {
         Err(err) => exit_with_error(PARSE_ERR, err),
       }
     },
-    Mode::Interpret { repl: do_repl } => {
+    Mode::Profile { top, config } => profile_hot_spots(in_file, top, debug_char, config),
+    Mode::Debug { input_file, config } => debug_program(in_file, input_file, debug_char, config),
+    Mode::Resume { snapshot_file } => resume_program(&snapshot_file),
+    Mode::Interpret { repl: do_repl, profile_file, stats, trace, checkpoint_file, config } => {
       if do_repl {
-        let mut repl = Repl::new();
-        repl.start();
+        let mut repl = Repl::new(config);
+        if let Err(err) = repl.start() {
+          exit_with_error(IO_ERR, err);
+        }
       }
       else {
         let data = match read_file(in_file) {
           Ok(data) => data,
           Err(err) => exit_with_error(GENERAL_ERR, err),
         };
-        let mut code = RawParser::new(data);
-        match code.parse() {
-          Ok(program) => program.run(),
+        let mut code = RawParser::new(data.clone()).debug_char(debug_char);
+        let program = match code.parse() {
+          Ok(program) => program,
           Err(err) => exit_with_error(PARSE_ERR, err),
+        };
+        let mut context = Context::with_config(config);
+        if let Some(trace) = trace {
+          let tracer = match trace.file {
+            Some(trace_file) => match File::create(trace_file) {
+              Ok(file) => Tracer::new(BufWriter::new(file), &data, trace.window, trace.limit),
+              Err(err) => exit_with_error(GENERAL_ERR, err),
+            },
+            None => Tracer::new(BufWriter::new(std::io::stderr()), &data, trace.window, trace.limit),
+          };
+          context.start_tracing(tracer);
+        }
+        // Only the stepping interpreter can stop part way through a loop and carry on later
+        if let Some(checkpoint_file) = checkpoint_file {
+          let stepper = Stepper::new(program.instructions(), context);
+          let snapshot = Snapshot { program: program_path(in_file), debug_char, ..Snapshot::default() };
+          run_with_checkpoints(stepper, &data, snapshot, &checkpoint_file);
+        }
+        // Statistics come from a profile, so only profiled runs pay for counting
+        else if stats || profile_file.is_some() {
+          let profile = match program.run_profiled(&mut context) {
+            Ok(profile) => profile,
+            Err(err) => exit_with_error(runtime_error_code(&err), err),
+          };
+          if stats {
+            eprint!("{}", program.stats(&profile, context.summary()));
+          }
+          if let Some(profile_file) = profile_file {
+            if let Err(err) = profile.save(profile_file) {
+              exit_with_error(GENERAL_ERR, err);
+            }
+          }
         }
+        else if let Err(err) = program.run(&mut context) {
+          exit_with_error(runtime_error_code(&err), err);
+        }
+      }
+    },
+  }
+}
+
+/// The command line interface, with `pass_names` as the names the pass arguments accept.
+fn app<'a>(pass_names: &'a [&'a str]) -> App<'a, 'a> {
+  App::new("Rustration")
+          .version("0.1")
+          .author("Daniel Seitz")
+          .about("A command line interpreter/compiler for Brainfuck")
+          .arg(Arg::with_name("optimize")
+               .short("O")
+               .long("optimize")
+               .help("Optimize the compiled output, -O0 through -O3 pick the optimization level and -O on its own is -O2, does nothing if you are running with -i"))
+          .args(&opt_level_args())
+          .group(ArgGroup::with_name("opt-level")
+               .args(&OPT_LEVEL_NAMES))
+          .arg(Arg::with_name("enable-pass")
+               .long("enable-pass")
+               .help("Run an optimization pass on top of the ones in the optimization level")
+               .value_name("PASS")
+               .takes_value(true)
+               .multiple(true)
+               .number_of_values(1)
+               .possible_values(pass_names))
+          .arg(Arg::with_name("disable-pass")
+               .long("disable-pass")
+               .help("Don't run an optimization pass, even if the optimization level includes it")
+               .value_name("PASS")
+               .takes_value(true)
+               .multiple(true)
+               .number_of_values(1)
+               .possible_values(pass_names))
+          .arg(Arg::with_name("print-after")
+               .long("print-after")
+               .help("Print the byte code after an optimization pass runs")
+               .value_name("PASS")
+               .takes_value(true)
+               .multiple(true)
+               .number_of_values(1)
+               .possible_values(pass_names))
+          .arg(Arg::with_name("eval-budget")
+               .long("eval-budget")
+               .help("The maximum number of instructions the partial-eval pass runs at compile time")
+               .value_name("STEPS")
+               .takes_value(true)
+               .validator(|steps| steps.parse::<usize>().map(|_| ()).map_err(|err| err.to_string())))
+          .arg(Arg::with_name("vector-width")
+               .long("vector-width")
+               .help("The width in bytes of the vector adds the vectorize pass emits, 32 needs a CPU with AVX2")
+               .value_name("BYTES")
+               .takes_value(true)
+               .possible_values(&["16", "32"]))
+          .arg(Arg::with_name("opt-report")
+               .long("opt-report")
+               .help("Print how much each optimization pass changed the program"))
+          .arg(Arg::with_name("profile-use")
+               .long("profile-use")
+               .help("Guide optimization with a profile written by --profile-generate, does nothing if you are running with -i")
+               .value_name("FILE")
+               .takes_value(true))
+          .arg(Arg::with_name("profile-generate")
+               .long("profile-generate")
+               .help("Write a profile of how often each loop ran, only works with -i")
+               .value_name("FILE")
+               .takes_value(true))
+          .arg(Arg::with_name("stats")
+               .long("stats")
+               .help("Print how many instructions of each kind ran, counted the same way as --max-steps, how far along the tape the program got and how long it took, only works with -i"))
+          .arg(Arg::with_name("trace")
+               .long("trace")
+               .help("Print the position of every instruction that runs along with the cells around the data pointer to stderr, only works with -i"))
+          .arg(Arg::with_name("trace-file")
+               .long("trace-file")
+               .help("Write the trace to this file instead of stderr, implies --trace")
+               .value_name("FILE")
+               .takes_value(true))
+          .arg(Arg::with_name("trace-window")
+               .long("trace-window")
+               .help("The number of cells either side of the data pointer the trace shows")
+               .value_name("CELLS")
+               .takes_value(true)
+               .validator(|cells| cells.parse::<usize>().map(|_| ()).map_err(|err| err.to_string())))
+          .arg(Arg::with_name("trace-limit")
+               .long("trace-limit")
+               .help("Stop tracing once the trace reaches this many bytes")
+               .value_name("BYTES")
+               .takes_value(true)
+               .validator(|bytes| bytes.parse::<u64>().map(|_| ()).map_err(|err| err.to_string())))
+          .arg(Arg::with_name("checkpoint")
+               .long("checkpoint")
+               .help("Write a snapshot of the program to this file if it's stopped with SIGINT or SIGTERM, so it can be carried on with the resume subcommand, only works with -i. The program runs without the interpreter's shortcuts for loops like [-], so it's slower")
+               .value_name("FILE")
+               .takes_value(true)
+               .conflicts_with_all(&["stats", "profile-generate"]))
+          .arg(Arg::with_name("assembly")
+               .short("S")
+               .long("assembly")
+               .help("Only run compile steps, does nothing if you are running with -i"))
+          .arg(Arg::with_name("no-link")
+               .short("-c")
+               .long("no-link")
+               .help("Only run compile and assemble steps, does nothgin if you are running with -i"))
+          .arg(Arg::with_name("output")
+               .short("o")
+               .long("out-file")
+               .help("Output file name, does nothing if you are running with -i")
+               .value_name("FILE")
+               .takes_value(true))
+          .arg(Arg::with_name("interpret")
+               .short("i")
+               .long("interpret")
+               .help("Interpret and run the input file without compiling"))
+          .args(&interpreter_args())
+          .arg(debug_char_arg())
+          .arg(Arg::with_name("INPUT")
+               .help("The input file to use or - for stdin")
+               .required(true))
+          .setting(AppSettings::SubcommandsNegateReqs)
+          .setting(AppSettings::ArgsNegateSubcommands)
+          .setting(AppSettings::DisableHelpSubcommand)
+          .subcommand(SubCommand::with_name("profile")
+               .about("Interpret a program and show where in the source it spends its time")
+               .args(&interpreter_args())
+               .arg(debug_char_arg())
+               .arg(Arg::with_name("top")
+                    .long("top")
+                    .help("The number of hottest loops to list")
+                    .value_name("N")
+                    .takes_value(true)
+                    .validator(|top| top.parse::<usize>().map(|_| ()).map_err(|err| err.to_string())))
+               .arg(Arg::with_name("INPUT")
+                    .help("The input file to profile")
+                    .required(true)))
+          .subcommand(SubCommand::with_name("debug")
+               .about("Step through a program with breakpoints at positions or at '#' characters")
+               .args(&interpreter_args())
+               .arg(debug_char_arg())
+               .arg(Arg::with_name("input")
+                    .long("input")
+                    .help("Read the program's input from this file instead of sharing stdin with the debugger")
+                    .value_name("FILE")
+                    .takes_value(true))
+               .arg(Arg::with_name("INPUT")
+                    .help("The input file to debug")
+                    .required(true)))
+          .subcommand(SubCommand::with_name("resume")
+               .about("Carry on running a program from a snapshot written by --checkpoint")
+               .arg(Arg::with_name("INPUT")
+                    .help("The snapshot file, it's replaced by a new snapshot if the program is stopped again")
+                    .required(true)))
+}
+
+/// The digits that follow -O to pick an optimization level.
+const OPT_LEVEL_NAMES: [&str; 4] = ["0", "1", "2", "3"];
+
+/// A hidden flag for each digit of an optimization level. Since -O doesn't take a value, clap
+/// reads -O3 as -O followed by -3, so the level can't swallow the input file after a bare -O.
+fn opt_level_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  OPT_LEVEL_NAMES.iter().map(|&level| Arg::with_name(level).short(level).hidden(true).requires("optimize")).collect()
+}
+
+/// The optimization level picked by -O and the flags added by `opt_level_args`. -O on its own is
+/// the default optimization level, leaving it out is no optimization.
+fn opt_level_of(matches: &ArgMatches) -> u8 {
+  match OPT_LEVEL_NAMES.iter().position(|&level| matches.is_present(level)) {
+    Some(level) => level as u8,
+    None if matches.is_present("optimize") => compile::DEFAULT_OPT_LEVEL,
+    None => 0,
+  }
+}
+
+/// The arguments that configure the interpreter, for interpreting with -i as well as the
+/// subcommands that interpret the program.
+fn interpreter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("cell-bits")
+      .long("cell-bits")
+      .help("The number of bits in each cell")
+      .value_name("BITS")
+      .takes_value(true)
+      .possible_values(&CELL_BITS_NAMES),
+    Arg::with_name("overflow")
+      .long("overflow")
+      .help("What happens when a cell goes past its largest value or below zero")
+      .value_name("BEHAVIOUR")
+      .takes_value(true)
+      .possible_values(&OVERFLOW_NAMES),
+    Arg::with_name("tape")
+      .long("tape")
+      .help("What happens when the data pointer moves off the tape: clamp, error, infinite, circular:<cells> or fixed:<cells>")
+      .value_name("POLICY")
+      .takes_value(true)
+      .validator(|policy| policy.parse::<TapePolicy>().map(|_| ())),
+    Arg::with_name("eof")
+      .long("eof")
+      .help("What ',' does to the current cell once there's no input left")
+      .value_name("POLICY")
+      .takes_value(true)
+      .possible_values(&EOF_NAMES),
+    Arg::with_name("flush")
+      .long("flush")
+      .help("When output is flushed, after every byte, after every newline or only before reading input and at exit")
+      .value_name("WHEN")
+      .takes_value(true)
+      .possible_values(&FLUSH_NAMES),
+    Arg::with_name("max-steps")
+      .long("max-steps")
+      .help("Stop the program after it runs this many instructions")
+      .value_name("STEPS")
+      .takes_value(true)
+      .validator(|steps| steps.parse::<u64>().map(|_| ()).map_err(|err| err.to_string())),
+    Arg::with_name("max-cells")
+      .long("max-cells")
+      .help("Stop the program if the tape grows past this many cells")
+      .value_name("CELLS")
+      .takes_value(true)
+      .validator(|cells| cells.parse::<usize>().map(|_| ()).map_err(|err| err.to_string())),
+    Arg::with_name("max-output")
+      .long("max-output")
+      .help("Stop the program after it outputs this many bytes")
+      .value_name("BYTES")
+      .takes_value(true)
+      .validator(|bytes| bytes.parse::<u64>().map(|_| ()).map_err(|err| err.to_string())),
+    Arg::with_name("timeout")
+      .long("timeout")
+      .help("Stop the program after it runs for this many seconds")
+      .value_name("SECONDS")
+      .takes_value(true)
+      .validator(|seconds| match seconds.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => Err(String::from("the timeout must be a non-negative number of seconds that isn't too large")),
+        Err(err) => Err(err.to_string()),
+      }),
+  ]
+}
+
+/// The argument that turns '#' into an instruction, for interpreting and compiling alike.
+fn debug_char_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("debug-char")
+    .long("debug-char")
+    .help("Treat '#' as an instruction that writes the data pointer and the cells around it to stderr, instead of as a comment")
+}
+
+/// Build the interpreter's configuration from the arguments added by `interpreter_args`.
+fn config_of(matches: &ArgMatches) -> Config {
+  Config {
+    cell_bits: matches.value_of("cell-bits").map_or(interpreter::DEFAULT_CELL_BITS, |bits| bits.parse().unwrap()),
+    overflow: matches.value_of("overflow").map_or(Overflow::Wrap, |overflow| overflow.parse().unwrap()),
+    tape: matches.value_of("tape").map_or(TapePolicy::Clamp, |policy| policy.parse().unwrap()),
+    eof: matches.value_of("eof").map_or(EofPolicy::Unchanged, |policy| policy.parse().unwrap()),
+    flush: matches.value_of("flush").map_or(Flush::Line, |flush| flush.parse().unwrap()),
+    limits: Limits {
+      fuel: matches.value_of("max-steps").map(|steps| steps.parse().unwrap()),
+      cells: matches.value_of("max-cells").map(|cells| cells.parse().unwrap()),
+      output: matches.value_of("max-output").map(|bytes| bytes.parse().unwrap()),
+      time: matches.value_of("timeout").map(|seconds| Duration::from_secs_f64(seconds.parse().unwrap())),
+    },
+  }
+}
+
+/// Interpret the program in `in_file` and print its source annotated with where it spent its time,
+/// along with its `top` hottest loops. '#' is an instruction if `debug_char` is set.
+fn profile_hot_spots(in_file: &str, top: usize, debug_char: bool, config: Config) {
+  let data = match read_file(in_file) {
+    Ok(data) => data,
+    Err(err) => exit_with_error(GENERAL_ERR, err),
+  };
+  let program = match RawParser::new(data.clone()).debug_char(debug_char).parse() {
+    Ok(program) => program,
+    Err(err) => exit_with_error(PARSE_ERR, err),
+  };
+  let mut context = Context::with_config(config);
+  let profile = match program.run_profiled(&mut context) {
+    Ok(profile) => profile,
+    Err(err) => exit_with_error(runtime_error_code(&err), err),
+  };
+  let stdout = std::io::stdout();
+  if let Err(err) = program.heat(&profile).report(&data, top, &mut stdout.lock()) {
+    exit_with_error(IO_ERR, err);
+  }
+}
+
+/// Step through the program in `in_file` with the debugger, giving it input from `input_file` or
+/// stdin. '#' is an instruction as well as a breakpoint if `debug_char` is set.
+fn debug_program(in_file: &str, input_file: Option<String>, debug_char: bool, config: Config) {
+  let data = match read_file(in_file) {
+    Ok(data) => data,
+    Err(err) => exit_with_error(GENERAL_ERR, err),
+  };
+  let program = match RawParser::new(data.clone()).debug_char(debug_char).parse() {
+    Ok(program) => program,
+    Err(err) => exit_with_error(PARSE_ERR, err),
+  };
+  let stdout = std::io::stdout();
+  let mut debugger = match input_file {
+    Some(input_file) => match File::open(input_file) {
+      Ok(input) => Debugger::new(&program, &data, config, input, stdout),
+      Err(err) => exit_with_error(GENERAL_ERR, err),
+    },
+    None => Debugger::new(&program, &data, config, ByteAtATime(std::io::stdin()), stdout),
+  };
+  // Commands are read a line at a time, without holding on to stdin, so the program can read
+  // from it as well
+  let next_command = || {
+    let mut line = String::new();
+    let num_read = std::io::stdin().read_line(&mut line)?;
+    Ok(if num_read == 0 { None } else { Some(line) })
+  };
+  if let Err(err) = debugger.run(next_command, &mut std::io::stdout()) {
+    exit_with_error(IO_ERR, err);
+  }
+}
+
+/// Carry on running the program from the snapshot in `snapshot_file`, writing a new snapshot over
+/// it if the program is stopped again.
+fn resume_program(snapshot_file: &str) {
+  let snapshot = match Snapshot::load(snapshot_file) {
+    Ok(snapshot) => snapshot,
+    Err(err) => exit_with_error(GENERAL_ERR, err),
+  };
+  let data = match read_file(&snapshot.program) {
+    Ok(data) => data,
+    Err(err) => exit_with_error(GENERAL_ERR, err),
+  };
+  if !snapshot.matches(&data) {
+    let message = format!("{} has changed since the snapshot was taken", snapshot.program);
+    exit_with_error(GENERAL_ERR, std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+  }
+  let program = match RawParser::new(data.clone()).debug_char(snapshot.debug_char).parse() {
+    Ok(program) => program,
+    Err(err) => exit_with_error(PARSE_ERR, err),
+  };
+  let context = Context::from_snapshot(&snapshot, std::io::stdin(), std::io::stdout());
+  let stepper = Stepper::resume(program.instructions(), context, snapshot.position);
+  run_with_checkpoints(stepper, &data, snapshot, snapshot_file);
+}
+
+/// Run the program in `stepper` to the end, unless SIGINT or SIGTERM stops it first. Then a
+/// snapshot is written to `checkpoint_file`, with its program fields taken from `program`, and
+/// the process exits.
+fn run_with_checkpoints(mut stepper: Stepper, source: &[u8], program: Snapshot, checkpoint_file: &str) {
+  catch_stop_signals();
+  match stepper.run(&STOP_REQUESTED) {
+    Ok(true) => {},
+    Ok(false) => {
+      if let Err(err) = stepper.context_mut().flush() {
+        exit_with_error(IO_ERR, err);
+      }
+      let snapshot = Snapshot {
+        program: program.program,
+        program_hash: interpreter::hash_source(source),
+        debug_char: program.debug_char,
+        ..stepper.snapshot()
+      };
+      if let Err(err) = snapshot.save(checkpoint_file) {
+        exit_with_error(IO_ERR, err);
       }
+      eprintln!("Stopped, carry on with: rustration resume {}", checkpoint_file);
+      std::process::exit(INTERRUPTED_ERR);
     },
+    Err(err) => exit_with_error(runtime_error_code(&err), err),
+  }
+}
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+  STOP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Set `STOP_REQUESTED` on SIGINT or SIGTERM instead of exiting straight away.
+///
+/// The handler is installed without `SA_RESTART`, so a read blocked on input fails with `EINTR`
+/// and the program can be stopped while it waits on ','.
+fn catch_stop_signals() {
+  let handler = request_stop as extern "C" fn(libc::c_int);
+  unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = 0;
+    libc::sigemptyset(&mut action.sa_mask);
+    libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+  }
+}
+
+/// The path of the program in `in_file` as it's kept in a snapshot, so the program can be found
+/// again from any directory.
+fn program_path(in_file: &str) -> String {
+  match std::fs::canonicalize(in_file) {
+    Ok(path) => path.to_string_lossy().into_owned(),
+    Err(_) => String::from(in_file),
+  }
+}
+
+/// Collect the optimization passes named by every occurrence of the argument `name`.
+fn passes_of(matches: &ArgMatches, name: &str) -> Vec<Pass> {
+  match matches.values_of(name) {
+    // clap has already checked these against the names of `PASSES`
+    Some(names) => names.map(|name| name.parse().unwrap()).collect(),
+    None => Vec::new(),
   }
 }
 
 fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
-  let mut file = try!(File::open(path));
+  let mut file = File::open(path)?;
   let mut buffer = match file.metadata() {
     Ok(metadata) => {
       let len = metadata.len();
@@ -243,7 +807,7 @@ fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
     Err(_) => Vec::new(),
   };
 
-  try!(file.read_to_end(&mut buffer));
+  file.read_to_end(&mut buffer)?;
   Ok(buffer)
 }
 
@@ -307,9 +871,19 @@ fn link(bin_path: &str) {
   }
 }
 
+/// The exit code for a program the interpreter stopped with `err`.
+fn runtime_error_code(err: &RuntimeError) -> i32 {
+  match *err {
+    RuntimeError::Overflow(..) | RuntimeError::Underflow(..) => OVERFLOW_ERR,
+    RuntimeError::MovedPastStart(..) | RuntimeError::MovedPastEnd(..) => TAPE_ERR,
+    RuntimeError::LimitExceeded(..) => LIMIT_ERR,
+    RuntimeError::Io(..) => IO_ERR,
+  }
+}
+
 fn exit_with_error<E: Error>(code: i32, err: E) -> ! {
   cleanup();
-  println!("{}", err);
+  eprintln!("{}", err);
   std::process::exit(code);
 }
 
@@ -327,3 +901,55 @@ fn cleanup() {
   std::fs::remove_file(asm_path).ok();
   std::fs::remove_file(obj_path).ok();
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn optimization_levels() {
+    let pass_names: Vec<&str> = PASSES.iter().map(Pass::name).collect();
+    let parse = |args: &[&str]| app(&pass_names).get_matches_from_safe(args.iter());
+    let level_and_input = |args: &[&str]| {
+      let matches = parse(args).unwrap();
+      (opt_level_of(&matches), String::from(matches.value_of("INPUT").unwrap()))
+    };
+    let input = String::from("prog.b");
+
+    // -O on its own never takes the input file as its level
+    assert_eq!(level_and_input(&["rustration", "-O", "prog.b"]), (compile::DEFAULT_OPT_LEVEL, input.clone()));
+    assert_eq!(level_and_input(&["rustration", "-S", "-o", "x", "-O", "prog.b"]), (compile::DEFAULT_OPT_LEVEL, input.clone()));
+    assert_eq!(level_and_input(&["rustration", "-O3", "prog.b"]), (3, input.clone()));
+    assert_eq!(level_and_input(&["rustration", "-O0", "-S", "prog.b"]), (0, input.clone()));
+    assert_eq!(level_and_input(&["rustration", "prog.b"]), (0, input));
+    assert!(parse(&["rustration", "-O4", "prog.b"]).is_err());
+    assert!(parse(&["rustration", "-O1", "-O2", "prog.b"]).is_err());
+    assert!(parse(&["rustration", "-3", "prog.b"]).is_err());
+  }
+
+  #[test]
+  fn input_named_like_a_subcommand() {
+    let pass_names: Vec<&str> = PASSES.iter().map(Pass::name).collect();
+    let input = |args: &[&str]| {
+      let matches = app(&pass_names).get_matches_from_safe(args.iter()).unwrap();
+      assert_eq!(matches.subcommand_name(), None);
+      String::from(matches.value_of("INPUT").unwrap())
+    };
+    assert_eq!(input(&["rustration", "hello.b"]), "hello.b");
+    assert_eq!(input(&["rustration", "-i", "debug.b"]), "debug.b");
+  }
+
+  #[test]
+  fn timeouts() {
+    let pass_names: Vec<&str> = PASSES.iter().map(Pass::name).collect();
+    let timeout = |seconds: &str| {
+      app(&pass_names).get_matches_from_safe(["rustration", "-i", "--timeout", seconds, "prog.b"])
+        .map(|matches| config_of(&matches).limits.time.unwrap())
+    };
+    assert_eq!(timeout("0").unwrap(), Duration::from_secs(0));
+    assert_eq!(timeout("1.5").unwrap(), Duration::from_millis(1500));
+    assert!(timeout("1e300").is_err());
+    assert!(timeout("NaN").is_err());
+    assert!(timeout("x").is_err());
+  }
+}
@@ -1,4 +1,3 @@
-
 //! Abstract Syntax Tree of the Rustration interpreter
 //! 
 //! This module contains representations of the abstract syntax tree for the Brainfuck interpreter.
@@ -25,10 +24,14 @@
 
 use super::parsing::parse;
 use super::parsing::Parser;
-use interpreter::Context;
-use std::collections::VecDeque;
+use super::{Span, Warning};
+use interpreter::{Context, Instruction, RunSummary, RuntimeError};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use compile::Compiler;
 use compile::ByteCode;
+use compile::{Idiom, Step, recognize_idiom};
+use profile::{Heat, Profile, Stats};
 
 /// Expressions
 /// 
@@ -55,6 +58,10 @@ pub enum Expr {
   /// Representing the ',' command, take a value as input and store it in the cell under the data
   /// pointer
   Input,
+
+  /// Representing the '#' command when it's been opted into, dump the data pointer and the cells
+  /// around it for debugging
+  Debug,
   
   /// Representing the loop contruct in Brainfuck, a pair of matching '[' and ']'.
   /// 
@@ -69,17 +76,20 @@ pub enum Expr {
 }
 
 impl Expr {
-  /// Execute the semantics of `self`'s variant in the given context.
-  pub fn run(&self, context: &mut Context) {
+  /// Execute the semantics of `self`'s variant in the given context, `span` is the position of the
+  /// expression.
+  pub fn run(&self, context: &mut Context, span: Span) -> Result<(), RuntimeError> {
     match *self {
-      Expr::MoveRight => context.move_right(),
-      Expr::MoveLeft => context.move_left(),
-      Expr::Increment => context.increment(),
-      Expr::Decrement => context.decrement(),
-      Expr::Output => context.output(),
-      Expr::Input => context.input(),
-      Expr::Loop(ref inner) => inner.run(context),
+      Expr::MoveRight => context.move_right(span)?,
+      Expr::MoveLeft => context.move_left(span)?,
+      Expr::Increment => context.increment(span)?,
+      Expr::Decrement => context.decrement(span)?,
+      Expr::Output => context.output(span)?,
+      Expr::Input => context.input(span)?,
+      Expr::Debug => context.dump(span)?,
+      Expr::Loop(ref inner) => inner.run(context)?,
     }
+    Ok(())
   }
 }
 
@@ -90,13 +100,56 @@ pub struct Program {
 
 impl Program {
   pub fn new(entry: Block) -> Self {
-    Program { entry: entry }
+    Program { entry }
+  }
+
+  /// Run the already parsed program in the given context, flushing its output once it stops.
+  pub fn run(&self, context: &mut Context) -> Result<RunSummary, RuntimeError> {
+    context.start_clock();
+    let result = self.entry.run(context);
+    let flushed = context.flush().map_err(|err| RuntimeError::Io(None, err));
+    result.and(flushed)?;
+    Ok(context.summary())
+  }
+
+  /// Run the already parsed program in the given context, counting how often each loop runs.
+  pub fn run_profiled(&self, context: &mut Context) -> Result<Profile, RuntimeError> {
+    context.start_profiling();
+    context.start_clock();
+    let result = self.entry.run(context);
+    let flushed = context.flush().map_err(|err| RuntimeError::Io(None, err));
+    result.and(flushed)?;
+    Ok(context.take_profile().unwrap_or_default())
+  }
+
+  /// Sum up a profile of the program, and the summary of the run it came from, into statistics
+  /// about the whole run.
+  pub fn stats(&self, profile: &Profile, summary: RunSummary) -> Stats {
+    let mut stats = Stats { summary, ..Stats::default() };
+    self.entry.count_kinds(1, profile, &mut stats);
+    stats
+  }
+
+  /// Flatten the program into a list of instructions, each with its position, that can be run one
+  /// at a time.
+  pub fn instructions(&self) -> Vec<(Instruction, Span)> {
+    let mut instructions = Vec::new();
+    self.entry.flatten(&mut instructions);
+    instructions
+  }
+
+  /// Lay a profile of the program over the positions of its instructions.
+  pub fn heat(&self, profile: &Profile) -> Heat {
+    let mut heat = Heat::default();
+    self.entry.add_heat(1, profile, &mut heat);
+    heat
   }
 
-  /// Run the already parsed program.
-  pub fn run(&self) {
-    let mut context = Context::new();
-    self.entry.run(&mut context);
+  /// Find loops that can never terminate.
+  pub fn warnings(&self) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    self.entry.check(&mut KnownTape::start(), &mut warnings);
+    warnings
   }
 
   pub fn compile<T: Compiler>(&self, compiler: &mut T) -> VecDeque<ByteCode> {
@@ -104,39 +157,236 @@ impl Program {
   }
 }
 
+/// What is known about the cells of the tape at some point in a program.
+struct KnownTape {
+  /// Cells whose value is known, or that are known to have been changed to an unknown value
+  cells: BTreeMap<isize, Option<u8>>,
+  pointer: isize,
+  /// The furthest right that the first cell of the tape could be
+  first: isize,
+  /// Whether every cell that isn't in `cells` still holds zero
+  rest_zero: bool,
+}
+
+impl KnownTape {
+  /// The tape at the start of the program, where every cell is zero.
+  fn start() -> Self {
+    KnownTape { cells: BTreeMap::new(), pointer: 0, first: 0, rest_zero: true }
+  }
+
+  /// A tape where nothing is known about any cell.
+  fn unknown() -> Self {
+    KnownTape { cells: BTreeMap::new(), pointer: 0, first: 0, rest_zero: false }
+  }
+
+  /// How many cells there are to the left of the data pointer, at least.
+  fn cells_to_left(&self) -> isize {
+    self.pointer - self.first
+  }
+
+  fn current(&self) -> Option<u8> {
+    match self.cells.get(&self.pointer) {
+      Some(&cell) => cell,
+      None if self.rest_zero => Some(0),
+      None => None,
+    }
+  }
+
+  fn add(&mut self, num: u8) {
+    let cell = self.current().map(|cell| cell.wrapping_add(num));
+    self.cells.insert(self.pointer, cell);
+  }
+}
+
+/// What running a block once does to the tape, relative to where the data pointer started.
+#[derive(Default)]
+struct Effect {
+  moved: isize,
+  /// The furthest left of where it started that the block takes the data pointer
+  lowest: isize,
+  changes: BTreeMap<isize, u8>,
+  /// Cells that a loop might have changed by some unknown amount
+  clobbered: BTreeSet<isize>,
+}
+
 /// A statement enclosing a series of expressions in order.
 /// 
 /// When this is evaluated it executes each expression stored in order from the start of the block
 /// to the end.
 #[derive(Debug)]
 pub struct Block {
-  block: VecDeque<Expr>,
+  // Each expression along with its position
+  block: VecDeque<(Expr, Span)>,
+  // The position of the ']' closing the block, when it's the body of a loop
+  close: Span,
 }
 
 impl Block {
   /// Create a new, empty `Block` statement.
   pub fn new() -> Self {
-    Block { block: VecDeque::new() }
+    Block { block: VecDeque::new(), close: Span::default() }
   }
 
-  /// Add an `Expr` to the block.
+  /// Add an `Expr` found at `span` to the block.
   /// 
   /// This expression is stored after any expressions already within the block.
-  pub fn add_expr(&mut self, expr: Expr) {
-    self.block.push_back(expr);
+  pub fn add_expr(&mut self, expr: Expr, span: Span) {
+    self.block.push_back((expr, span));
+  }
+
+  /// Record that the block is the body of a loop closed by the ']' at `span`.
+  pub fn close_at(&mut self, span: Span) {
+    self.close = span;
   }
 
   /// Execute all expressions stored in the block.
-  pub fn run(&self, context: &mut Context) {
-    for expr in self.block.iter() {
-      expr.run(context);
+  pub fn run(&self, context: &mut Context) -> Result<(), RuntimeError> {
+    for &(ref expr, span) in self.block.iter() {
+      context.tick(span)?;
+      expr.run(context, span)?;
+    }
+    Ok(())
+  }
+
+  /// Add up how often each kind of instruction in the block ran from the loop counts in
+  /// `profile`, given that the block ran `count` times.
+  fn count_kinds(&self, count: u64, profile: &Profile, stats: &mut Stats) {
+    for (expr, _) in self.block.iter() {
+      let counts = &mut stats.instructions;
+      match *expr {
+        Expr::MoveRight => counts.move_right += count,
+        Expr::MoveLeft => counts.move_left += count,
+        Expr::Increment => counts.increment += count,
+        Expr::Decrement => counts.decrement += count,
+        Expr::Output => counts.output += count,
+        Expr::Input => counts.input += count,
+        Expr::Debug => counts.debug += count,
+        Expr::Loop(ref inner) => {
+          counts.loops += count;
+          let iterations = profile.loop_counts(inner.id).iterations;
+          stats.loop_iterations += iterations;
+          inner.block.count_kinds(iterations, profile, stats);
+        },
+      }
     }
   }
 
+  /// Add the instructions in the block onto the end of `instructions`.
+  fn flatten(&self, instructions: &mut Vec<(Instruction, Span)>) {
+    for &(ref expr, span) in self.block.iter() {
+      let instruction = match *expr {
+        Expr::MoveRight => Instruction::MoveRight,
+        Expr::MoveLeft => Instruction::MoveLeft,
+        Expr::Increment => Instruction::Increment,
+        Expr::Decrement => Instruction::Decrement,
+        Expr::Output => Instruction::Output,
+        Expr::Input => Instruction::Input,
+        Expr::Debug => Instruction::Debug,
+        Expr::Loop(ref inner) => {
+          // The '[' is filled in once the loop's end is known
+          let start = instructions.len();
+          instructions.push((Instruction::LoopStart(0), span));
+          inner.block.flatten(instructions);
+          let end = instructions.len();
+          instructions[start].0 = Instruction::LoopStart(end);
+          instructions.push((Instruction::LoopEnd(start), inner.block.close));
+          continue;
+        },
+      };
+      instructions.push((instruction, span));
+    }
+  }
+
+  /// Add how often every instruction in the block ran to `heat`, from the loop counts in
+  /// `profile`, given that the block ran `count` times.
+  fn add_heat(&self, count: u64, profile: &Profile, heat: &mut Heat) {
+    for &(ref expr, span) in self.block.iter() {
+      heat.add_instruction(span, count);
+      if let Expr::Loop(ref inner) = *expr {
+        let counts = profile.loop_counts(inner.id);
+        heat.loops.push((inner.span, counts));
+        inner.block.add_heat(counts.iterations, profile, heat);
+      }
+    }
+  }
+
+  /// Warn about loops in the block that never terminate, given what's known about the tape before
+  /// the block runs.
+  fn check(&self, tape: &mut KnownTape, warnings: &mut Vec<Warning>) {
+    for (expr, _) in self.block.iter() {
+      match *expr {
+        Expr::MoveRight => tape.pointer += 1,
+        // Moving left from the first cell does something different under each tape policy, so
+        // nothing is known about the tape after it
+        Expr::MoveLeft if tape.cells_to_left() == 0 => *tape = KnownTape::unknown(),
+        Expr::MoveLeft => tape.pointer -= 1,
+        Expr::Increment => tape.add(1),
+        Expr::Decrement => tape.add(255),
+        Expr::Output | Expr::Debug => {},
+        Expr::Input => { tape.cells.insert(tape.pointer, None); },
+        Expr::Loop(ref inner) => {
+          let entry = tape.current();
+          if entry == Some(0) {
+            // The loop never runs
+            continue;
+          }
+          if inner.never_terminates(tape.cells_to_left()) {
+            warnings.push(match entry {
+              Some(_) => Warning::InfiniteLoop(inner.span.line, inner.span.column),
+              None => Warning::NonTerminatingLoop(inner.span.line, inner.span.column),
+            });
+          }
+          else {
+            inner.block.check(&mut KnownTape::unknown(), warnings);
+          }
+          // The only thing known after a loop is that it left the data pointer on a zero cell
+          *tape = KnownTape::unknown();
+          tape.cells.insert(0, Some(0));
+        },
+      }
+    }
+  }
+
+  /// What running the block does to the tape, if it does no I/O and every loop in it leaves the
+  /// data pointer where it started.
+  fn effect(&self) -> Option<Effect> {
+    let mut effect = Effect::default();
+    for (expr, _) in self.block.iter() {
+      match *expr {
+        Expr::MoveRight => effect.moved += 1,
+        Expr::MoveLeft => {
+          effect.moved -= 1;
+          effect.lowest = cmp::min(effect.lowest, effect.moved);
+        },
+        Expr::Increment | Expr::Decrement => {
+          let num = if let Expr::Increment = *expr { 1 } else { 255 };
+          let cell = effect.changes.entry(effect.moved).or_insert(0);
+          *cell = cell.wrapping_add(num);
+        },
+        Expr::Output | Expr::Input => return None,
+        // Dumping the tape doesn't change it
+        Expr::Debug => {},
+        Expr::Loop(ref inner) => {
+          let inner_effect = inner.block.effect()?;
+          if inner_effect.moved != 0 {
+            return None;
+          }
+          effect.lowest = cmp::min(effect.lowest, effect.moved + inner_effect.lowest);
+          let touched = inner_effect.changes.keys().chain(inner_effect.clobbered.iter());
+          for offset in touched {
+            effect.clobbered.insert(effect.moved + offset);
+          }
+          effect.clobbered.insert(effect.moved);
+        },
+      }
+    }
+    Some(effect)
+  }
+
   pub fn compile<T: Compiler>(&self, compiler: &mut T) -> VecDeque<ByteCode> {
     let mut byte_code = VecDeque::new();
-    for expr in self.block.iter() {
-      byte_code.append(&mut compiler.compile_expr(expr));
+    for &(ref expr, span) in self.block.iter() {
+      byte_code.append(&mut compiler.compile_expr(expr, span));
     }
     byte_code
   }
@@ -146,21 +396,65 @@ impl Block {
 #[derive(Debug)]
 pub struct Loop {
   block: Block,
+  idiom: Option<Idiom>,
+  id: usize,
+  span: Span,
 }
 
 impl Loop {
   /// Create a new `Loop`, parsing all the tokens stored after the initial '[' up until a matching
-  /// ']' is found.
-  pub fn new<T: Parser>(code: &mut T) -> super::Result<Self> {
-    let block = try!(parse(code, false));
-    Ok(Loop { block: block })
+  /// ']' is found. `span` is the position of the '['.
+  pub fn new<T: Parser>(code: &mut T, span: Span) -> super::Result<Self> {
+    let id = code.next_loop_id();
+    let block = parse(code, None)?;
+    let idiom = recognize_idiom(block.block.iter().map(|(expr, _)| match *expr {
+      Expr::MoveRight => Step::Move(1),
+      Expr::MoveLeft => Step::Move(-1),
+      Expr::Increment => Step::Add(1),
+      Expr::Decrement => Step::Add(-1),
+      _ => Step::Other,
+    }));
+    Ok(Loop { block, idiom, id, span })
+  }
+
+  /// Whether the loop can never change the cell it checks, so it never terminates once it's
+  /// entered with at least `cells_to_left` cells to the left of the data pointer.
+  /// 
+  /// A loop that might move left from the first cell is never reported, since whether that moves
+  /// the data pointer at all depends on the tape policy.
+  fn never_terminates(&self, cells_to_left: isize) -> bool {
+    match self.block.effect() {
+      Some(effect) => {
+        effect.moved == 0 && effect.lowest + cells_to_left >= 0 &&
+          effect.changes.get(&0).is_none_or(|&change| change == 0) && !effect.clobbered.contains(&0)
+      },
+      None => false,
+    }
   }
 
   /// Execute the expressions within the loop as long as the conditions for looping are met.
-  fn run(&self, context: &mut Context) {
+  fn run(&self, context: &mut Context) -> Result<(), RuntimeError> {
+    // Idioms run in one go still count every iteration they stand in for, and every instruction
+    // in those iterations, so profiles and step counts come out the same either way
+    let mut iterations = match self.idiom {
+      Some(ref idiom) if context.can_shortcut(idiom) => match *idiom {
+        Idiom::Clear(step) => context.clear(step),
+        Idiom::Scan(step) => context.scan(step),
+        Idiom::Multiply(ref targets) => context.multiply(targets),
+      },
+      _ => 0,
+    };
+    context.skip_steps(iterations * (self.block.block.len() as u64 + 1));
+    // Idioms that couldn't be completed in one go fall back to iterating over the body. The '['
+    // was counted on the way into the loop, after that it's the ']' that checks the cell at the
+    // end of every iteration
     while !context.current_cell_is_zero() {
-      self.block.run(context);
+      self.block.run(context)?;
+      context.tick(self.block.close)?;
+      iterations += 1;
     }
+    context.record_loop(self.id, iterations);
+    Ok(())
   }
 
   pub fn compile<T: Compiler>(&self, compiler: &mut T) -> VecDeque<ByteCode> {
@@ -172,14 +466,15 @@ impl Loop {
 mod tests {
   use super::*;
   use parse::RawParser;
+  use profile::LoopCounts;
 
   #[test]
   fn add_expr_to_block() {
     let mut block = Block::new();
     assert_eq!(block.block.len(), 0);
 
-    block.add_expr(Expr::MoveLeft);
-    block.add_expr(Expr::MoveRight);
+    block.add_expr(Expr::MoveLeft, Span::new(1, 1));
+    block.add_expr(Expr::MoveRight, Span::new(1, 2));
 
     assert_eq!(block.block.len(), 2);
   }
@@ -189,15 +484,179 @@ mod tests {
     let mut parser = RawParser::new(vec![b'>', b']']);
     parser.increment_nest_level();
 
-    let loop_expr = Loop::new(&mut parser);
+    let loop_expr = Loop::new(&mut parser, Span::new(1, 1));
     assert_eq!(loop_expr.unwrap().block.block.len(), 1);
   }
 
+  #[test]
+  fn classify_loops() {
+    let classify = |code: &[u8]| {
+      let mut parser = RawParser::new(code.to_vec());
+      parser.increment_nest_level();
+      Loop::new(&mut parser, Span::new(1, 1)).unwrap().idiom
+    };
+
+    assert_eq!(classify(b"-]"), Some(Idiom::Clear(-1)));
+    assert_eq!(classify(b"+++]"), Some(Idiom::Clear(3)));
+    assert_eq!(classify(b"--]"), None);
+    assert_eq!(classify(b">]"), Some(Idiom::Scan(1)));
+    assert_eq!(classify(b"<<<]"), Some(Idiom::Scan(-3)));
+    assert_eq!(classify(b"><]"), None);
+    assert_eq!(classify(b"->]"), None);
+    assert_eq!(classify(b"->+>++<<]"), Some(Idiom::Multiply(vec![(1, 1), (2, 2)])));
+    assert_eq!(classify(b"-[>+<-]]"), None);
+  }
+
+  #[test]
+  fn profile_program() {
+    let program = RawParser::new(b"+++[>++[-]<-]>[.]".to_vec()).parse().unwrap();
+    let profile = program.run_profiled(&mut Context::default()).unwrap();
+
+    assert_eq!(profile.loop_counts(0), LoopCounts { entries: 1, iterations: 3 });
+    assert_eq!(profile.loop_counts(1), LoopCounts { entries: 3, iterations: 6 });
+    assert_eq!(profile.loop_counts(2), LoopCounts { entries: 1, iterations: 0 });
+    assert_eq!(profile.to_string(), "loop 0 1 3\nloop 1 3 6\nloop 2 1 0\n");
+  }
+
+  #[test]
+  fn program_stats() {
+    use profile::InstructionCounts;
+
+    let program = RawParser::new(b"+++[>++[-]<-]>[.]".to_vec()).parse().unwrap();
+    let mut context = Context::default();
+    let profile = program.run_profiled(&mut context).unwrap();
+    let stats = program.stats(&profile, context.summary());
+
+    let counts = InstructionCounts {
+      move_right: 4,
+      move_left: 3,
+      increment: 9,
+      decrement: 9,
+      output: 0,
+      input: 0,
+      debug: 0,
+      loops: 5,
+    };
+    assert_eq!(stats.instructions, counts);
+    assert_eq!(stats.loop_iterations, 9);
+    // Every instruction and loop iteration is a step
+    assert_eq!(stats.summary.steps, 4 + 3 + 9 + 9 + 5 + 9);
+    // so the total printed is the one the fuel limit goes by
+    let printed = stats.to_string();
+    assert!(printed.starts_with(&format!("Instructions executed: {}\n", stats.summary.steps)));
+    assert!(printed.contains("\n  [  5\n  ]  9\n"));
+    assert_eq!((stats.summary.highest_cell, stats.summary.pointer), (1, 1));
+  }
+
+  #[test]
+  fn program_heat() {
+    let program = RawParser::new(b"+++\n[>++[-]<-]\n>[.]".to_vec()).parse().unwrap();
+    let profile = program.run_profiled(&mut Context::default()).unwrap();
+    let heat = program.heat(&profile);
+
+    assert_eq!(heat.lines, vec![Some(3), Some(25), Some(2)]);
+    assert_eq!(heat.hottest_loops(2), vec![
+      (Span::new(2, 5), LoopCounts { entries: 3, iterations: 6 }),
+      (Span::new(2, 1), LoopCounts { entries: 1, iterations: 3 }),
+    ]);
+  }
+
+  #[test]
+  fn warn_about_infinite_loops() {
+    let warnings = |code: &str| RawParser::new(code.as_bytes().to_vec()).parse().unwrap().warnings();
+
+    assert_eq!(warnings("+[]"), vec![Warning::InfiniteLoop(1, 2)]);
+    assert_eq!(warnings("+\n>+<[>+<]"), vec![Warning::InfiniteLoop(2, 4)]);
+    assert_eq!(warnings(",[>[-]+<]"), vec![Warning::NonTerminatingLoop(1, 2)]);
+    assert_eq!(warnings(",[.]"), vec![]);
+    assert_eq!(warnings(",[>+<-]"), vec![]);
+    assert_eq!(warnings(",[>[-<+>]<]"), vec![]);
+    // '<' doesn't move off the first cell under the default tape policy
+    assert_eq!(warnings("+[<>]"), vec![]);
+    assert_eq!(warnings("+>+[<<>>]"), vec![]);
+    assert_eq!(warnings("+[>[<>]<]"), vec![Warning::InfiniteLoop(1, 2)]);
+    assert_eq!(warnings(">+[<>]"), vec![Warning::InfiniteLoop(1, 3)]);
+    assert_eq!(warnings("+[-]>+[<>]"), vec![Warning::NonTerminatingLoop(1, 7)]);
+    // Loops that are never entered are fine
+    assert_eq!(warnings("[]+[-][]"), vec![]);
+  }
+
+  #[test]
+  fn run_with_streams() {
+    use interpreter::{Config, EofPolicy};
+
+    let program = RawParser::new(b",[.,]".to_vec()).parse().unwrap();
+    let mut output = Vec::new();
+    {
+      let input = &b"bytes \x80\xff"[..];
+      let config = Config { eof: EofPolicy::Zero, ..Config::default() };
+      let mut context = Context::with_streams(config, input, &mut output);
+      let summary = program.run(&mut context).unwrap();
+      assert_eq!((summary.cells, summary.output_bytes), (1, 8));
+    }
+    assert_eq!(output, b"bytes \x80\xff");
+  }
+
+  #[test]
+  fn trace_program() {
+    use interpreter::Tracer;
+
+    let source = b"+[-]";
+    let program = RawParser::new(source.to_vec()).parse().unwrap();
+    let mut trace = Vec::new();
+    {
+      let mut context = Context::default();
+      context.start_tracing(Tracer::new(&mut trace, source, 1, None));
+      program.run(&mut context).unwrap();
+    }
+    let expected = "1:1 + pointer 0 cells 0: [0]\n\
+                    1:2 [ pointer 0 cells 0: [1]\n\
+                    1:3 - pointer 0 cells 0: [1]\n\
+                    1:4 ] pointer 0 cells 0: [0]\n";
+    assert_eq!(String::from_utf8(trace).unwrap(), expected);
+  }
+
+  #[test]
+  fn io_errors() {
+    use std::io::{self, Write};
+    use interpreter::{Config, Flush};
+
+    struct Broken;
+
+    impl Write for Broken {
+      fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::BrokenPipe))
+      }
+
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+
+    let program = RawParser::new(b"+\n+.".to_vec()).parse().unwrap();
+    let broken = |span| Err(RuntimeError::Io(span, io::Error::from(io::ErrorKind::BrokenPipe)));
+    let mut context = Context::with_streams(Config { flush: Flush::Always, ..Config::default() }, io::empty(), Broken);
+    assert_eq!(program.run(&mut context), broken(Some(Span::new(2, 2))));
+    // Buffered output only fails once it's flushed at the end
+    let mut context = Context::with_streams(Config::default(), io::empty(), Broken);
+    assert_eq!(program.run(&mut context), broken(None));
+  }
+
+  #[test]
+  fn trap_on_overflow() {
+    use interpreter::{Config, Overflow};
+
+    let program = RawParser::new(b"+>+<[>[-]<-]\n>--".to_vec()).parse().unwrap();
+    let mut context = Context::with_config(Config { overflow: Overflow::Trap, ..Config::default() });
+    assert_eq!(program.run(&mut context), Err(RuntimeError::Underflow(Span::new(2, 2), 1)));
+    assert!(program.run(&mut Context::default()).is_ok());
+  }
+
   #[test]
   fn non_matching_loop_errors() {
     let mut parser = RawParser::new(vec![b'>', b'<']);
     parser.increment_nest_level();
 
-    assert!(Loop::new(&mut parser).is_err());
+    assert!(Loop::new(&mut parser, Span::new(1, 1)).is_err());
   }
 }
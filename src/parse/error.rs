@@ -33,7 +33,7 @@ impl Error for ParseError {
     }
   }
 
-  fn cause(&self) -> Option<&Error> {
+  fn cause(&self) -> Option<&dyn Error> {
     None
   }
 }
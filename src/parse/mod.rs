@@ -15,10 +15,12 @@
 pub mod ast;
 mod token;
 mod error;
+mod warning;
 mod parsing;
 
-pub use self::token::EOF;
+pub use self::token::{EOF, Span};
 pub use self::parsing::{ReplParser, RawParser};
+pub use self::warning::Warning;
 use std;
 
 pub type Result<T> = std::result::Result<T, error::ParseError>;
@@ -16,6 +16,9 @@ pub trait Parser {
   fn increment_nest_level(&mut self);
   fn decrement_nest_level(&mut self);
   fn nest_level(&self) -> usize;
+
+  /// Get the id of the next loop, loops are numbered in the order their '[' appears.
+  fn next_loop_id(&mut self) -> usize;
 }
 
 pub struct ReplParser {
@@ -30,7 +33,7 @@ impl Parser for ReplParser {
     loop {
       match self.inner.next_token() {
         None => {
-          if let Err(_) = self.status_channel.send(Status::Ready) {
+          if self.status_channel.send(Status::Ready).is_err() {
             return Some(self.inner.eof_token());
           }
           match self.data_channel.recv().ok() {
@@ -54,6 +57,10 @@ impl Parser for ReplParser {
   fn nest_level(&self) -> usize {
     self.inner.nest_level()
   }
+
+  fn next_loop_id(&mut self) -> usize {
+    self.inner.next_loop_id()
+  }
 }
 
 impl ReplParser {
@@ -61,14 +68,14 @@ impl ReplParser {
   pub fn new(data_channel: Receiver<Vec<u8>>, status_channel: Sender<Status>) -> Self {
     ReplParser {
       inner: RawParser::new(Vec::new()),
-      data_channel: data_channel,
-      status_channel: status_channel,
+      data_channel,
+      status_channel,
     }
   }
 
-  /// Parse the program and execute the code as it is being parsed.
-  pub fn parse_and_run(&mut self) -> Result<Program> {
-    let entry = parse(self, true);
+  /// Parse the program and execute the code in `context` as it is being parsed.
+  pub fn parse_and_run(&mut self, context: &mut Context) -> Result<Program> {
+    let entry = parse(self, Some(context));
     self.status_channel.send(Status::Exited).ok();
     entry.map(Program::new)
   }
@@ -79,6 +86,9 @@ pub struct RawParser {
   code: Vec<u8>,
   current_index: usize,
   nesting: usize,
+  loop_count: usize,
+  // Whether '#' is an instruction rather than a comment
+  debug_char: bool,
 
   line_num: usize,
   char_num: usize,
@@ -89,7 +99,10 @@ impl Parser for RawParser {
   fn next_token(&mut self) -> Option<MetaToken> {
     if self.current_index < self.code.len() {
       let raw_token = self.code[self.current_index];
-      let token = Token::from(self.code[self.current_index]);
+      let token = match raw_token {
+        b'#' if self.debug_char => Token::Debug,
+        raw_token => Token::from(raw_token),
+      };
       let ret = MetaToken::new(token, self.line_num, self.char_num);
       self.current_index += 1;
       if raw_token == b'\n' {
@@ -115,6 +128,11 @@ impl Parser for RawParser {
   fn nest_level(&self) -> usize {
     self.nesting
   }
+
+  fn next_loop_id(&mut self) -> usize {
+    self.loop_count += 1;
+    self.loop_count - 1
+  }
 }
 
 impl RawParser {
@@ -124,18 +142,27 @@ impl RawParser {
       code: data,
       current_index: 0,
       nesting: 0,
+      loop_count: 0,
+      debug_char: false,
       line_num: 1,
       char_num: 1,
     }
   }
 
+  /// Treat '#' as an instruction that dumps the data pointer and the cells around it, rather than
+  /// as a comment.
+  pub fn debug_char(mut self, enabled: bool) -> Self {
+    self.debug_char = enabled;
+    self
+  }
+
   fn eof_token(&self) -> MetaToken {
     MetaToken::new(Token::Eof, self.line_num, self.char_num)
   }
 
   /// Parse the program.
   pub fn parse(&mut self) -> Result<Program> {
-    let entry = try!(parse(self, false));
+    let entry = parse(self, None)?;
     Ok(Program::new(entry))
   }
 
@@ -143,10 +170,9 @@ impl RawParser {
 
 /// Loop through each byte of data given for a program and parse it into our AST.
 /// 
-/// Optionaly execute the expressions as they are evaluated.
-pub fn parse<T: Parser>(parser: &mut T, run: bool) -> Result<Block> {
+/// Optionaly execute the expressions in `context` as they are evaluated.
+pub fn parse<T: Parser>(parser: &mut T, mut context: Option<&mut Context>) -> Result<Block> {
   let mut block = Block::new();
-  let mut context = Context::new();
 
   let mut start_line = None;
   let mut start_char = None;
@@ -160,6 +186,7 @@ pub fn parse<T: Parser>(parser: &mut T, run: bool) -> Result<Block> {
     };
     let line = meta_token.line();
     let character = meta_token.character();
+    let span = meta_token.span();
     if start_line.is_none() {
       start_line = Some(line);
 
@@ -177,15 +204,17 @@ pub fn parse<T: Parser>(parser: &mut T, run: bool) -> Result<Block> {
       Token::Decrement => Expr::Decrement,
       Token::Output => Expr::Output,
       Token::Input => Expr::Input,
+      Token::Debug => Expr::Debug,
       Token::JumpForward => {
         parser.increment_nest_level();
-        Expr::Loop(try!(Loop::new(parser)))
+        Expr::Loop(Loop::new(parser, span)?)
       },
       Token::JumpBack => {
         if parser.nest_level() == 0 {
           return Err(ParseError::UnmatchedCloseBrace(line, character));
         }
         parser.decrement_nest_level();
+        block.close_at(span);
         return Ok(block);
       },
       Token::Comment => continue,
@@ -197,10 +226,16 @@ pub fn parse<T: Parser>(parser: &mut T, run: bool) -> Result<Block> {
       }
     };
 
-    if parser.nest_level() == 0 && run {
-      expr.run(&mut context);
+    if parser.nest_level() == 0 {
+      if let Some(ref mut context) = context {
+        // Each command typed into the REPL gets the whole time limit to itself
+        context.start_clock();
+        if let Err(err) = expr.run(context, span) {
+          eprintln!("{}", err);
+        }
+      }
     }
-    block.add_expr(expr);
+    block.add_expr(expr, span);
   }
   Ok(block)
 }
@@ -217,7 +252,7 @@ mod tests {
     use std::time::{Instant, Duration};
     const NUM_TESTS: u32 = 1000;
     let mut sum = Duration::new(0, 0);
-    let data = ::read_file("test_files/life.b");
+    let data = ::read_file("test_files/life.b").unwrap();
 
     for _ in 0..NUM_TESTS {
       let mut parser = RawParser::new(data.clone());
@@ -239,7 +274,7 @@ mod tests {
 
     let mut parser = ReplParser::new(data_rx, status_tx);
     std::thread::spawn(move|| {
-      assert!(parser.parse_and_run().is_ok());
+      assert!(parser.parse_and_run(&mut Context::default()).is_ok());
     });
     assert_eq!(status_rx.recv().unwrap(), Status::Ready);
     assert!(data_tx.send(vec![b'+', b'+', b'>', b'<']).is_ok());
@@ -255,7 +290,7 @@ mod tests {
 
     let mut parser = ReplParser::new(data_rx, status_tx);
     std::thread::spawn(move|| {
-      assert!(parser.parse_and_run().is_err());
+      assert!(parser.parse_and_run(&mut Context::default()).is_err());
     });
     assert_eq!(status_rx.recv().unwrap(), Status::Ready);
     assert!(data_tx.send(vec![b'+', b'+', b'>', b'<', b'[']).is_ok());
@@ -284,26 +319,34 @@ mod tests {
 
     let mut token;
 
-    token = parser.next_token();
+    token = parser.next_token().unwrap();
     assert_eq!(token.token(), &Token::MoveRight);
 
-    token = parser.next_token();
+    token = parser.next_token().unwrap();
     assert_eq!(token.token(), &Token::MoveLeft);
 
-    token = parser.next_token();
+    token = parser.next_token().unwrap();
     assert_eq!(token.token(), &Token::Increment);
 
-    token = parser.next_token();
+    token = parser.next_token().unwrap();
     assert_eq!(token.token(), &Token::Increment);
 
-    token = parser.next_token();
+    token = parser.next_token().unwrap();
     assert_eq!(token.token(), &Token::Output);
 
-    token = parser.next_token();
-    assert_eq!(token.token(), &Token::Eof);
-    token = parser.next_token();
-    assert_eq!(token.token(), &Token::Eof);
-    token = parser.next_token();
-    assert_eq!(token.token(), &Token::Eof);
+    assert!(parser.next_token().is_none());
+    assert!(parser.next_token().is_none());
+    assert!(parser.next_token().is_none());
+  }
+
+  #[test]
+  fn debug_char_is_opt_in() {
+    let mut parser = RawParser::new(b"+#".to_vec());
+    parser.next_token();
+    assert_eq!(parser.next_token().unwrap().token(), &Token::Comment);
+
+    let mut parser = RawParser::new(b"+#".to_vec()).debug_char(true);
+    parser.next_token();
+    assert_eq!(parser.next_token().unwrap().token(), &Token::Debug);
   }
 }
@@ -8,6 +8,20 @@
 /// Value marking the end of the Brainfuck file.
 pub const EOF: u8 = 255;
 
+/// The position of a token in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Span {
+  /// Create a new `Span` at the specified line and column.
+  pub fn new(line: usize, column: usize) -> Self {
+    Span { line, column }
+  }
+}
+
 /// Metadata wrapping type.
 /// 
 /// This type wraps a `Token` type with metadata about the positioning of the token in the file,
@@ -23,9 +37,9 @@ impl MetaToken {
   /// Create a new `MetaToken` wrapping the corresponding `Token` at the specified line and column.
   pub fn new(token: Token, line: usize, character: usize) -> Self {
     MetaToken {
-      token: token,
-      line: line,
-      character: character,
+      token,
+      line,
+      character,
     }
   }
 
@@ -43,6 +57,11 @@ impl MetaToken {
   pub fn character(&self) -> usize {
     self.character
   }
+
+  /// Get the position of the token.
+  pub fn span(&self) -> Span {
+    Span::new(self.line, self.character)
+  }
 }
 
 /// Token types
@@ -75,6 +94,9 @@ pub enum Token {
   /// The ']' character, jump back to the matching '[' if the cell under the data pointer is not 0.
   JumpBack,
   
+  /// The '#' character when it's been opted into, dump the data pointer and the cells around it.
+  Debug,
+
   /// Any other character, these are ignored and have no effect.
   Comment,
 
@@ -0,0 +1,27 @@
+// parse/warning.rs
+// Rustration
+
+use std;
+
+/// Problems with a program that don't stop it from being run or compiled.
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+  /// A loop that's always entered with a non-zero cell and can never change it
+  InfiniteLoop(usize, usize),
+
+  /// A loop that can never change its cell, so it never terminates if it's entered
+  NonTerminatingLoop(usize, usize),
+}
+
+impl std::fmt::Display for Warning {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Warning::InfiniteLoop(line, column) => {
+        write!(f, "Infinite loop starting at line: {}, column: {}", line, column)
+      },
+      Warning::NonTerminatingLoop(line, column) => {
+        write!(f, "Loop starting at line: {}, column: {} never terminates if it's entered", line, column)
+      },
+    }
+  }
+}
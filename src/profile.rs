@@ -0,0 +1,277 @@
+//! Execution profiles
+//!
+//! A profile records how often each part of a Brainfuck program ran while it was being
+//! interpreted. Loops are identified by the order their '[' appears in the source, which is the
+//! same order the compiler numbers its loop labels in. How often every other instruction ran
+//! follows from how often the loops around it ran, so only loops are recorded.
+//!
+//! Profiles are stored as plain text, one record per line:
+//!
+//! ```text
+//! loop <id> <entries> <iterations>
+//! ```
+//!
+//! A profile can also be summed up into `Stats` about what the program did over the whole run,
+//! or laid over the program's source as a `Heat` map of where it spent its time.
+
+use std;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use interpreter::RunSummary;
+use parse::Span;
+
+/// How often a single loop ran.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LoopCounts {
+  /// The number of times the loop was reached
+  pub entries: u64,
+
+  /// The total number of times the body of the loop ran
+  pub iterations: u64,
+}
+
+/// Execution counts for a single run of a program.
+///
+/// Only loops are counted. Per-instruction counts were planned too, but every one of them follows
+/// from the counts of the loops around it and no optimization pass reads them, so writing them
+/// out would only make profiles bigger. `Program::heat` and `Program::stats` work them out from
+/// the loop counts instead.
+#[derive(Debug, Default, PartialEq)]
+pub struct Profile {
+  loops: Vec<LoopCounts>,
+}
+
+impl Profile {
+  /// Create an empty profile.
+  pub fn new() -> Self {
+    Profile::default()
+  }
+
+  /// Record that the loop with the given id was reached and ran its body `iterations` times.
+  pub fn record_loop(&mut self, id: usize, iterations: u64) {
+    let counts = self.loop_counts_mut(id);
+    counts.entries += 1;
+    counts.iterations += iterations;
+  }
+
+  fn loop_counts_mut(&mut self, id: usize) -> &mut LoopCounts {
+    if id >= self.loops.len() {
+      self.loops.resize(id + 1, LoopCounts::default());
+    }
+    &mut self.loops[id]
+  }
+
+  /// The counts for the loop with the given id, a loop that never ran has no entries.
+  pub fn loop_counts(&self, id: usize) -> LoopCounts {
+    self.loops.get(id).cloned().unwrap_or_default()
+  }
+
+  /// Read a profile from a file.
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+    data.parse().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+  }
+
+  /// Write the profile to a file.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    write!(file, "{}", self)
+  }
+}
+
+impl fmt::Display for Profile {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (id, counts) in self.loops.iter().enumerate() {
+      writeln!(f, "loop {} {} {}", id, counts.entries, counts.iterations)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Profile {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut profile = Profile::new();
+    for (line_num, line) in s.lines().enumerate() {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      let numbers: std::result::Result<Vec<u64>, _> = fields.iter().skip(1).map(|field| field.parse()).collect();
+      let error = || format!("Invalid profile record at line {}: {}", line_num + 1, line);
+      match (fields.first(), numbers.map_err(|_| error())?.as_slice()) {
+        (None, _) => {},
+        (Some(&"loop"), &[id, entries, iterations]) => {
+          *profile.loop_counts_mut(id as usize) = LoopCounts { entries, iterations };
+        },
+        _ => return Err(error()),
+      }
+    }
+    Ok(profile)
+  }
+}
+
+/// The number of times each kind of instruction ran.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InstructionCounts {
+  pub move_right: u64,
+  pub move_left: u64,
+  pub increment: u64,
+  pub decrement: u64,
+  pub output: u64,
+  pub input: u64,
+
+  /// The number of '#' tape dumps, these only run when '#' has been opted into
+  pub debug: u64,
+
+  /// The number of times a loop was reached, not counting its iterations
+  pub loops: u64,
+}
+
+/// Statistics about a single run of a program.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+  /// How often each kind of instruction ran
+  pub instructions: InstructionCounts,
+
+  /// The total number of times the body of any loop ran
+  pub loop_iterations: u64,
+
+  /// Everything else the interpreter kept track of during the run
+  pub summary: RunSummary,
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let counts = &self.instructions;
+    // The total is counted the same way as the fuel limit, the ']' at the end of every iteration
+    // is an instruction as well as the '[' reached on the way into the loop
+    writeln!(f, "Instructions executed: {}", self.summary.steps)?;
+    for &(name, count) in [(">", counts.move_right), ("<", counts.move_left), ("+", counts.increment),
+                           ("-", counts.decrement), (".", counts.output), (",", counts.input),
+                           ("[", counts.loops), ("]", self.loop_iterations)].iter() {
+      writeln!(f, "  {:<2} {}", name, count)?;
+    }
+    if counts.debug > 0 {
+      writeln!(f, "  {:<2} {}", "#", counts.debug)?;
+    }
+    writeln!(f, "Loop iterations: {}", self.loop_iterations)?;
+    writeln!(f, "Highest cell: {}", self.summary.highest_cell)?;
+    writeln!(f, "Final pointer: {}", self.summary.pointer)?;
+    writeln!(f, "Bytes read: {}", self.summary.input_bytes)?;
+    writeln!(f, "Bytes written: {}", self.summary.output_bytes)?;
+    writeln!(f, "Wall time: {:.3} seconds", self.summary.elapsed.as_secs_f64())
+  }
+}
+
+/// Execution counts laid over the source of a program.
+#[derive(Debug, Default, PartialEq)]
+pub struct Heat {
+  /// The number of instructions that ran on each line, starting from line 1, lines without any
+  /// instructions have no count
+  pub lines: Vec<Option<u64>>,
+
+  /// The position of every loop's '[' along with how often the loop ran
+  pub loops: Vec<(Span, LoopCounts)>,
+}
+
+impl Heat {
+  /// Add `count` runs of an instruction at `span`.
+  pub fn add_instruction(&mut self, span: Span, count: u64) {
+    if span.line > self.lines.len() {
+      self.lines.resize(span.line, None);
+    }
+    let line = &mut self.lines[span.line - 1];
+    *line = Some(line.unwrap_or(0) + count);
+  }
+
+  /// The `top` loops whose bodies ran the most, hottest first.
+  pub fn hottest_loops(&self, top: usize) -> Vec<(Span, LoopCounts)> {
+    let mut loops = self.loops.clone();
+    // Ties go to the loop that comes first in the source
+    loops.sort_by(|&(span, counts), &(other_span, other_counts)| {
+      other_counts.iterations.cmp(&counts.iterations)
+        .then((span.line, span.column).cmp(&(other_span.line, other_span.column)))
+    });
+    loops.truncate(top);
+    loops
+  }
+
+  /// Write out `source` with the number of instructions that ran on each line beside it, followed
+  /// by the `top` hottest loops.
+  pub fn report<W: Write>(&self, source: &[u8], top: usize, out: &mut W) -> io::Result<()> {
+    writeln!(out, "{:>12}  {:>5}", "count", "line")?;
+    for (line_num, line) in source.split(|&byte| byte == b'\n').enumerate() {
+      let count = match self.lines.get(line_num) {
+        Some(&Some(count)) => count.to_string(),
+        _ => String::new(),
+      };
+      writeln!(out, "{:>12}  {:>5} | {}", count, line_num + 1, String::from_utf8_lossy(line))?;
+    }
+    writeln!(out)?;
+    writeln!(out, "Hottest loops:")?;
+    for (span, counts) in self.hottest_loops(top) {
+      writeln!(out, "  line: {}, column: {}: {} iterations over {} entries",
+               span.line, span.column, counts.iterations, counts.entries)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn record_loops() {
+    let mut profile = Profile::new();
+    profile.record_loop(2, 10);
+    profile.record_loop(2, 5);
+
+    assert_eq!(profile.loop_counts(2), LoopCounts { entries: 2, iterations: 15 });
+    assert_eq!(profile.loop_counts(0), LoopCounts::default());
+    assert_eq!(profile.loop_counts(7), LoopCounts::default());
+  }
+
+  #[test]
+  fn profile_round_trip() {
+    let mut profile = Profile::new();
+    profile.record_loop(1, 42);
+    profile.record_loop(3, 0);
+
+    let text = profile.to_string();
+    assert_eq!(text.parse::<Profile>(), Ok(profile));
+  }
+
+  #[test]
+  fn heat_report() {
+    let mut heat = Heat::default();
+    heat.add_instruction(Span::new(1, 1), 3);
+    heat.add_instruction(Span::new(1, 2), 1);
+    heat.add_instruction(Span::new(3, 1), 0);
+    heat.loops.push((Span::new(1, 2), LoopCounts { entries: 1, iterations: 2 }));
+    heat.loops.push((Span::new(3, 1), LoopCounts { entries: 1, iterations: 5 }));
+    assert_eq!(heat.lines, vec![Some(4), None, Some(0)]);
+
+    let mut report = Vec::new();
+    heat.report(b"+[-]\ncomment\n[]", 1, &mut report).unwrap();
+    let expected = "       count   line\n\
+                    \x20          4      1 | +[-]\n\
+                    \x20                 2 | comment\n\
+                    \x20          0      3 | []\n\
+                    \n\
+                    Hottest loops:\n\
+                    \x20 line: 3, column: 1: 5 iterations over 1 entries\n";
+    assert_eq!(String::from_utf8(report).unwrap(), expected);
+  }
+
+  #[test]
+  fn invalid_profile_errors() {
+    assert!("loop 1 2".parse::<Profile>().is_err());
+    assert!("loop a 2 3".parse::<Profile>().is_err());
+    assert!("insn 0 2".parse::<Profile>().is_err());
+    assert!("jump 1 2".parse::<Profile>().is_err());
+  }
+}